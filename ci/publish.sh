@@ -3,7 +3,8 @@
 . ./ci/preamble.sh
 
 cargo_publish() {
-    cargo publish --all-features --quiet
+    cargo publish --package testnet-macros --quiet
+    cargo publish --package testnet --all-features --quiet
 }
 
 if test "$GITHUB_ACTIONS" = "true" && test "$GITHUB_REF_TYPE" != "tag"; then
@@ -3,8 +3,10 @@
 . ./ci/preamble.sh
 
 test_all() {
+    cargo clippy --workspace --all-targets "$@" -- -D warnings
     cargo test --workspace --quiet --no-run "$@"
     cargo test --workspace --no-fail-fast "$@" -- --nocapture
 }
 
 test_all --no-default-features
+test_all --all-features
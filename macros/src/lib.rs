@@ -0,0 +1,82 @@
+/*!
+Procedural macros for [testnet](https://docs.rs/testnet) crate.
+
+Use them via `testnet` crate with `macros` feature enabled.
+*/
+
+use std::time::Duration;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse_macro_input;
+use syn::Expr;
+use syn::ItemFn;
+use syn::LitStr;
+
+use self::duration::parse_duration;
+
+// the same parser as in the command line tool
+#[path = "../../src/duration.rs"]
+mod duration;
+
+/// Run the function on each node of the virtual network as a test.
+///
+/// The function takes `Context` and returns either `()` or `Result<(), E>`.
+///
+/// Properties:
+/// - `nodes = N` — the number of nodes (2 by default);
+/// - `timeout = "30s"` — fail if the nodes do not finish in time (60 seconds by default);
+///   the supported units are `ns`, `us`, `ms` and `s`, and the number can be fractional (e.g. `"1.5s"`).
+///
+/// The test is skipped (and passes) if Linux namespaces are not available.
+/// Node failures are reported as panics with the output of each node.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut nodes: Option<Expr> = None;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("nodes") {
+            nodes = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("timeout") {
+            let value: LitStr = meta.value()?.parse()?;
+            timeout =
+                parse_duration(&value.value()).map_err(|e| syn::Error::new(value.span(), e))?;
+            Ok(())
+        } else {
+            Err(meta.error("unsupported property, expected `nodes` or `timeout`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+    if let Some(asyncness) = function.sig.asyncness.as_ref() {
+        return syn::Error::new_spanned(asyncness, "use `testnet::async_main` in async tests")
+            .to_compile_error()
+            .into();
+    }
+    let nodes = nodes.map_or_else(|| quote!(2), |nodes| quote!(#nodes));
+    let timeout_secs = timeout.as_secs();
+    let timeout_subsec_nanos = timeout.subsec_nanos();
+    let attrs = &function.attrs;
+    let vis = &function.vis;
+    let name = &function.sig.ident;
+    let inner = ItemFn {
+        attrs: Vec::new(),
+        ..function.clone()
+    };
+    quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #name() {
+            #inner
+            ::testnet::__private::run_test(
+                #nodes,
+                ::std::time::Duration::new(#timeout_secs, #timeout_subsec_nanos),
+                |context| ::testnet::__private::TestResult::into_result(#name(context)),
+            );
+        }
+    }
+    .into()
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
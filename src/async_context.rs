@@ -0,0 +1,233 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::os::fd::RawFd;
+
+use nix::fcntl::fcntl;
+use nix::fcntl::FcntlArg;
+use nix::fcntl::OFlag;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use crate::CallbackResult;
+use crate::Context;
+use crate::Error;
+use crate::IpcMessage;
+
+/// Asynchronous node execution context.
+///
+/// This is the same as [`Context`] but the collective operations are futures
+/// that do not block the runtime's worker thread.
+/// Use [`async_main`] to obtain this context in the node's `main` function.
+///
+/// Node configuration is available via [`Deref`] to [`Context`].
+pub struct AsyncContext {
+    // Drop the registrations before the file descriptors are closed.
+    reader: AsyncFd<RawFd>,
+    writer: AsyncFd<RawFd>,
+    context: Context,
+}
+
+impl AsyncContext {
+    /// Make IPC pipes of the context non-blocking and register them in the current runtime.
+    pub fn new(context: Context) -> Result<Self, Error> {
+        let reader_fd = context.ipc_client.input_raw_fd();
+        let writer_fd = context.ipc_client.output_raw_fd();
+        fcntl(reader_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        fcntl(writer_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        Ok(Self {
+            reader: AsyncFd::with_interest(reader_fd, Interest::READABLE)?,
+            writer: AsyncFd::with_interest(writer_fd, Interest::WRITABLE)?,
+            context,
+        })
+    }
+
+    /// Name the current step.
+    ///
+    /// Testnet will output the name of the step on failure.
+    pub fn step(&mut self, name: impl Display) {
+        self.context.step(name)
+    }
+
+    /// Broadcast from one node to every other one.
+    pub fn broadcast_one(&mut self) -> AsyncBroadcastOne<'_> {
+        AsyncBroadcastOne { context: self }
+    }
+
+    /// Broadcast data from each node to each node.
+    ///
+    /// The data is received by each node in a vec where each index corresponds to the node index.
+    pub async fn broadcast_all(&mut self, data: Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
+        self.context.next_step();
+        let response = self.call(&IpcMessage::BroadcastAllSend(data)).await?;
+        let all_data = match response {
+            IpcMessage::BroadcastAllRecv(payload) => payload,
+            _ => return Err(Error::Ipc("invalid response".into())),
+        };
+        self.context.print_step();
+        Ok(all_data)
+    }
+
+    /// Convenience wrapper around `broadcast_all` that receives a string instead of arbitrary data.
+    pub async fn broadcast_all_string(&mut self, data: String) -> Result<Vec<String>, Error> {
+        self.broadcast_all(data.into())
+            .await?
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| Error::Io(std::io::Error::other(e)))
+    }
+
+    /// Store the value under the key in the network-wide key-value store.
+    ///
+    /// See [`Context::kv_put`].
+    pub async fn kv_put(&mut self, key: impl Into<String>, value: Vec<u8>) -> Result<(), Error> {
+        self.send(&IpcMessage::KvPut(key.into(), value)).await?;
+        Ok(())
+    }
+
+    /// Get the value of the key from the network-wide key-value store.
+    ///
+    /// See [`Context::kv_get`].
+    pub async fn kv_get(&mut self, key: impl Into<String>) -> Result<Vec<u8>, Error> {
+        let response = self.call(&IpcMessage::KvGet(key.into())).await?;
+        match response {
+            IpcMessage::KvValue(value) => Ok(value),
+            _ => Err(Error::Ipc("invalid response".into())),
+        }
+    }
+
+    async fn send(&mut self, message: &IpcMessage) -> Result<(), std::io::Error> {
+        let client = &mut self.context.ipc_client;
+        client.send(message)?;
+        loop {
+            let mut guard = self.writer.writable().await?;
+            if client.flush()? {
+                return Ok(());
+            }
+            guard.clear_ready();
+        }
+    }
+
+    async fn call(&mut self, message: &IpcMessage) -> Result<IpcMessage, std::io::Error> {
+        self.send(message).await?;
+        let client = &mut self.context.ipc_client;
+        loop {
+            if let Some(message) = client.recv()? {
+                return Ok(message);
+            }
+            let mut guard = self.reader.readable().await?;
+            match client.fill_buf() {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "no response"))
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => guard.clear_ready(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Deref for AsyncContext {
+    type Target = Context;
+
+    fn deref(&self) -> &Self::Target {
+        &self.context
+    }
+}
+
+/// Asynchronous broadcast from one node to every other one.
+///
+/// See [`BroadcastOne`](crate::BroadcastOne) for details.
+pub struct AsyncBroadcastOne<'a> {
+    context: &'a mut AsyncContext,
+}
+
+impl<'a> AsyncBroadcastOne<'a> {
+    /// Send the data by the initiator.
+    ///
+    /// This function marks the the end of the current step.
+    pub async fn send(self, data: Vec<u8>) -> Result<(), Error> {
+        self.context.context.next_step();
+        let response = self.context.call(&IpcMessage::Send(data)).await?;
+        if !matches!(response, IpcMessage::Wait) {
+            return Err(Error::Ipc("invalid response".into()));
+        }
+        self.context.context.print_step();
+        Ok(())
+    }
+
+    /// Convenience wrapper around `send` that sends a string instead of arbitrary data.
+    pub async fn send_string(self, data: String) -> Result<(), Error> {
+        self.send(data.into()).await
+    }
+
+    /// Receive the data from the initiator.
+    ///
+    /// This function marks the the end of the current step.
+    pub async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.context.context.next_step();
+        let response = self.context.call(&IpcMessage::Receive).await?;
+        let data = match response {
+            IpcMessage::Send(data) => data,
+            _ => return Err(Error::Ipc("invalid response".into())),
+        };
+        self.context.context.print_step();
+        Ok(data)
+    }
+
+    /// Convenience wrapper around `recv` that receives a string instead of arbitrary data.
+    pub async fn recv_string(mut self) -> Result<String, Error> {
+        let data = self.recv().await?;
+        String::from_utf8(data).map_err(|e| Error::Io(std::io::Error::other(e)))
+    }
+
+    /// Wait until broadcast is finished without sending or receiving any data.
+    pub async fn wait(self) -> Result<(), Error> {
+        self.context.context.next_step();
+        let response = self.context.call(&IpcMessage::Wait).await?;
+        if !matches!(response, IpcMessage::Wait) {
+            return Err(Error::Ipc("invalid response".into()));
+        }
+        self.context.context.print_step();
+        Ok(())
+    }
+}
+
+/// Convert asynchronous `main` function into the one that can be used in [`NetConfig`](crate::NetConfig).
+///
+/// The returned function drives the future on a per-node single-threaded Tokio runtime.
+///
+/// ```rust
+/// use testnet::async_main;
+/// use testnet::testnet;
+/// use testnet::NetConfig;
+/// use testnet::NodeConfig;
+/// let config = NetConfig {
+///     main: async_main(|mut context| async move {
+///         let i = context.current_node_index();
+///         let all_data = context.broadcast_all_string(i.to_string()).await?;
+///         assert_eq!(vec!["0", "1"], all_data);
+///         Ok(())
+///     }),
+///     nodes: vec![NodeConfig::default(); 2],
+/// };
+/// testnet(config).unwrap();
+/// ```
+pub fn async_main<F, R, T>(main: F) -> impl FnOnce(Context) -> CallbackResult<T> + Clone
+where
+    F: FnOnce(AsyncContext) -> R + Clone,
+    R: Future<Output = CallbackResult<T>>,
+{
+    move |context| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async move {
+            let context = AsyncContext::new(context)?;
+            main(context).await
+        })
+    }
+}
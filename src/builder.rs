@@ -0,0 +1,148 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bincode::Decode;
+use bincode::Encode;
+use ipnet::IpNet;
+
+use crate::assign_addresses;
+use crate::Error;
+use crate::Impairments;
+use crate::NetConfig;
+use crate::NetOptions;
+use crate::Network;
+use crate::NodeConfig;
+use crate::NodeMain;
+use crate::OutputConfig;
+
+/// Builder for [`Network`].
+///
+/// Unlike [`NetConfig`] it can be extended with new options without breaking the existing code.
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use testnet::Network;
+/// let network = Network::builder()
+///     .nodes(["server", "client"])
+///     .subnet("10.1.0.0/24".parse().unwrap())
+///     .timeout(Duration::from_secs(10))
+///     .build(|context| Ok(context.current_node_index()))
+///     .unwrap();
+/// for outcome in network.wait().unwrap() {
+///     assert!(outcome.is_success());
+/// }
+/// ```
+#[derive(Default, Clone)]
+pub struct NetworkBuilder {
+    nodes: Vec<NodeConfig>,
+    subnet: Option<IpNet>,
+    impairments: Impairments,
+    options: NetOptions,
+}
+
+impl NetworkBuilder {
+    /// Create the builder for the network without nodes and with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the node.
+    pub fn node(mut self, node: impl Into<NodeConfig>) -> Self {
+        self.nodes.push(node.into());
+        self
+    }
+
+    /// Add the nodes.
+    pub fn nodes<C: Into<NodeConfig>, I: IntoIterator<Item = C>>(mut self, nodes: I) -> Self {
+        self.nodes.extend(nodes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Assign the addresses from the subnet to the nodes that do not have one.
+    ///
+    /// Node `i` gets host address `i` of the subnet.
+    /// The default subnet is `10.84.0.0/16`.
+    pub fn subnet(mut self, subnet: IpNet) -> Self {
+        self.subnet = Some(subnet);
+        self
+    }
+
+    /// Impair the outgoing traffic of the nodes that do not have their own impairments.
+    pub fn impairments(mut self, impairments: Impairments) -> Self {
+        self.impairments = impairments;
+        self
+    }
+
+    /// Replace all the network-wide options.
+    pub fn options(mut self, options: NetOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See [`NetOptions::timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// See [`NetOptions::pause_on_failure`].
+    pub fn pause_on_failure(mut self, duration: Duration) -> Self {
+        self.options.pause_on_failure = Some(duration);
+        self
+    }
+
+    /// See [`NetOptions::skip_unsupported`].
+    pub fn skip_unsupported(mut self, skip: bool) -> Self {
+        self.options.skip_unsupported = skip;
+        self
+    }
+
+    /// See [`NetOptions::privileged`].
+    pub fn privileged(mut self, privileged: bool) -> Self {
+        self.options.privileged = privileged;
+        self
+    }
+
+    /// See [`NetOptions::output`].
+    pub fn output(mut self, output: OutputConfig) -> Self {
+        self.options.output = output;
+        self
+    }
+
+    /// See [`NetOptions::event_log`].
+    pub fn event_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.event_log = Some(path.into());
+        self
+    }
+
+    /// See [`NetOptions::trace`].
+    pub fn trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.trace = Some(path.into());
+        self
+    }
+
+    /// See [`NetOptions::junit`].
+    pub fn junit(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.junit = Some(path.into());
+        self
+    }
+
+    /// Launch the network that runs `main` on each node.
+    ///
+    /// See [`Network::new`].
+    pub fn build<F: NodeMain + Clone>(self, main: F) -> Result<Network<F::Value>, Error>
+    where
+        F::Value: Encode + Decode,
+    {
+        let mut nodes = self.nodes;
+        if let Some(subnet) = self.subnet {
+            assign_addresses(&mut nodes, subnet)?;
+        }
+        for node in nodes.iter_mut() {
+            if node.impairments.is_empty() {
+                node.impairments = self.impairments;
+            }
+        }
+        Network::with_options(NetConfig { nodes, main }, self.options)
+    }
+}
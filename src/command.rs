@@ -0,0 +1,49 @@
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::CallbackResult;
+use crate::Context;
+
+/// Returns node's `main` function that runs the command in the node's namespaces.
+///
+/// `command` builds the command for each node.
+/// The command is spawned after the node's network is configured and inherits
+/// the node's stdout and stderr, its IPC channel (see [`Context::from_env`])
+/// and its unix socket (see [`SOCKET_ENV`](crate::SOCKET_ENV)).
+/// Unlike `main` closures that run in the node process cloned from the current process,
+/// the command has its own stack and does not inherit the state of the current process,
+/// hence it can be your real release binary.
+///
+/// The node fails if the command exits with non-zero status.
+///
+/// ```rust,no_run
+/// use std::process::Command;
+/// use testnet::command_main;
+/// use testnet::testnet;
+/// use testnet::NetConfig;
+/// let config = NetConfig {
+///     main: command_main(|context| {
+///         let mut command = Command::new("./target/release/server");
+///         command.arg("--name").arg(context.current_node_name());
+///         command
+///     }),
+///     nodes: vec!["a", "b"],
+/// };
+/// testnet(config).unwrap();
+/// ```
+pub fn command_main<F>(command: F) -> impl FnOnce(Context) -> CallbackResult + Clone
+where
+    F: FnOnce(&Context) -> Command + Clone,
+{
+    move |context| {
+        let mut command = command(&context);
+        // stdin is closed in the node process
+        let status = command.stdin(Stdio::null()).status()?;
+        // the command uses the IPC channel, hence the context is kept until it exits
+        drop(context);
+        if !status.success() {
+            return Err(format!("command failed: {}", status).into());
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,321 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use ipnet::IpNet;
+use nix::unistd::Uid;
 
 use crate::Context;
 
 /// Result of the node's `main` function.
-pub type CallbackResult = Result<(), Box<dyn std::error::Error>>;
+///
+/// The value is sent back to the process that launched the network.
+pub type CallbackResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+
+/// Node's `main` function.
+///
+/// This trait is implemented for any closure that receives [`Context`]
+/// and returns [`CallbackResult`].
+pub trait NodeMain: FnOnce(Context) -> CallbackResult<<Self as NodeMain>::Value> {
+    /// The type of the value returned by the function.
+    type Value;
+}
+
+impl<F, T> NodeMain for F
+where
+    F: FnOnce(Context) -> CallbackResult<T>,
+{
+    type Value = T;
+}
 
 /// Network configuration.
 ///
 /// This includes the `main` function that is executed on each node
 /// and configuration of all the nodes.
-pub struct NetConfig<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackResult> {
+pub struct NetConfig<C: Into<NodeConfig>, F: NodeMain> {
     /// Nodes' configurations.
     pub nodes: Vec<C>,
     /// Closure that is run on each node.
     pub main: F,
 }
 
+/// Network-wide options.
+///
+/// Pass them to [`Network::with_options`](crate::Network::with_options)
+/// or [`testnet_with_options`](crate::testnet_with_options).
+#[derive(Default, Clone)]
+#[non_exhaustive]
+pub struct NetOptions {
+    /// Fail with [`Error::Timeout`](crate::Error::Timeout)
+    /// if the nodes do not finish in the specified time.
+    pub timeout: Option<Duration>,
+    /// Freeze the nodes and keep their namespaces alive when a node fails or the network times out.
+    ///
+    /// The instructions for entering the nodes' namespaces are printed to stderr,
+    /// and the nodes are terminated when the user presses enter or the specified time expires.
+    pub pause_on_failure: Option<Duration>,
+    /// Fail with [`Error::Skipped`](crate::Error::Skipped) instead of
+    /// [`Error::Namespace`](crate::Error::Namespace)
+    /// if Linux namespaces are not available (see [`is_supported`](crate::is_supported)).
+    ///
+    /// Useful in tests that should not fail on restricted CI runners.
+    pub skip_unsupported: bool,
+    /// Do not create user namespace and do not write uid/gid mappings
+    /// if the current process runs as real root.
+    ///
+    /// Has no effect for non-root users.
+    pub privileged: bool,
+    /// How the nodes' output is handled.
+    pub output: OutputConfig,
+    /// Write the timeline of the run to the specified file in JSON Lines format.
+    ///
+    /// Each line is an object with `time` in seconds since the network start
+    /// and `event` field that is one of
+    /// `node_spawned`, `node_exited`, `step`, `collective_enter`, `collective`, `output`, `message`, `timeout`.
+    pub event_log: Option<PathBuf>,
+    /// Write the spans of nodes, named steps and collective operations to the specified file
+    /// in Chrome trace event format.
+    ///
+    /// The file can be opened in Perfetto or `chrome://tracing`.
+    /// Each node is shown as a separate thread.
+    pub trace: Option<PathBuf>,
+    /// Write JUnit XML report to the specified file.
+    ///
+    /// Each node is a test case, and the output of the failed nodes is attached to their failures.
+    pub junit: Option<PathBuf>,
+}
+
+impl NetOptions {
+    /// Set [`timeout`](Self::timeout).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set [`pause_on_failure`](Self::pause_on_failure).
+    pub fn with_pause_on_failure(mut self, duration: Duration) -> Self {
+        self.pause_on_failure = Some(duration);
+        self
+    }
+
+    /// Set [`skip_unsupported`](Self::skip_unsupported).
+    pub fn with_skip_unsupported(mut self, skip: bool) -> Self {
+        self.skip_unsupported = skip;
+        self
+    }
+
+    /// Set [`privileged`](Self::privileged).
+    pub fn with_privileged(mut self, privileged: bool) -> Self {
+        self.privileged = privileged;
+        self
+    }
+
+    /// Set [`output`](Self::output).
+    pub fn with_output(mut self, output: OutputConfig) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Set [`event_log`](Self::event_log).
+    pub fn with_event_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.event_log = Some(path.into());
+        self
+    }
+
+    /// Set [`trace`](Self::trace).
+    pub fn with_trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace = Some(path.into());
+        self
+    }
+
+    /// Set [`junit`](Self::junit).
+    pub fn with_junit(mut self, path: impl Into<PathBuf>) -> Self {
+        self.junit = Some(path.into());
+        self
+    }
+
+    pub(crate) fn is_privileged(&self) -> bool {
+        self.privileged && Uid::current().is_root()
+    }
+}
+
+/// How the nodes' output is handled.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct OutputConfig {
+    /// Where the nodes' stdout goes.
+    pub stdout: OutputRoute,
+    /// Where the nodes' stderr goes.
+    pub stderr: OutputRoute,
+    /// When to print the output to the parent's stderr.
+    pub print: PrintMode,
+    /// Write each node's stdout and stderr to `<dir>/<node-name>.log`.
+    ///
+    /// The directory is created if it does not exist.
+    /// Discarded streams are not written.
+    pub dir: Option<PathBuf>,
+    /// Prefix each printed line with the time elapsed since the network start.
+    pub timestamps: bool,
+    /// Colour node names in the printed lines if stderr is a terminal.
+    pub colors: bool,
+    /// The number of last lines of each node's output that are kept in memory.
+    ///
+    /// These lines are reported in [`NodeReport::output`](crate::NodeReport::output).
+    pub buffer_len: usize,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            stdout: Default::default(),
+            stderr: Default::default(),
+            print: Default::default(),
+            dir: None,
+            timestamps: false,
+            colors: true,
+            buffer_len: 20,
+        }
+    }
+}
+
+impl OutputConfig {
+    /// Set [`stdout`](Self::stdout).
+    pub fn with_stdout(mut self, route: OutputRoute) -> Self {
+        self.stdout = route;
+        self
+    }
+
+    /// Set [`stderr`](Self::stderr).
+    pub fn with_stderr(mut self, route: OutputRoute) -> Self {
+        self.stderr = route;
+        self
+    }
+
+    /// Set [`print`](Self::print).
+    pub fn with_print(mut self, print: PrintMode) -> Self {
+        self.print = print;
+        self
+    }
+
+    /// Set [`dir`](Self::dir).
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Set [`timestamps`](Self::timestamps).
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Set [`colors`](Self::colors).
+    pub fn with_colors(mut self, colors: bool) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Set [`buffer_len`](Self::buffer_len).
+    pub fn with_buffer_len(mut self, buffer_len: usize) -> Self {
+        self.buffer_len = buffer_len;
+        self
+    }
+}
+
+/// Where the node's output stream goes.
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub enum OutputRoute {
+    /// Print each line to the parent's stderr prefixed with the node name
+    /// (see [`OutputConfig::print`]).
+    #[default]
+    Stderr,
+    /// Write the lines as is to the specified file.
+    ///
+    /// The file is shared by all the nodes that use the same path
+    /// and is truncated when the network starts.
+    File(PathBuf),
+    /// Discard the lines.
+    Discard,
+}
+
+/// When to print the nodes' output to the parent's stderr.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PrintMode {
+    /// Print each line as soon as it is received.
+    #[default]
+    Always,
+    /// Print the lines that are kept in memory if some nodes failed.
+    OnFailure,
+    /// Do not print the output.
+    Never,
+}
+
 /// Node configuration.
 #[derive(Default, Clone)]
 pub struct NodeConfig {
     /// Host name.
+    ///
+    /// Also used as the name of the node's log file, hence `/`, `.` and `..` are rejected.
     pub name: String,
     /// Network interface address.
     pub ifaddr: IpNet,
+    /// Impairments of the outgoing traffic.
+    pub impairments: Impairments,
+    /// The role of the node that chooses its `main` function in [`NodeMains`](crate::NodeMains).
+    ///
+    /// Empty by default.
+    pub role: String,
+}
+
+/// Impairments of the node's outgoing traffic.
+///
+/// Applied with `netem` queueing discipline.
+/// The network fails with [`Error::Netlink`](crate::Error::Netlink)`(ENOENT)`
+/// if `sch_netem` kernel module is not available.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct Impairments {
+    /// Delay of each packet.
+    pub delay: Duration,
+    /// Random variation of the delay.
+    pub jitter: Duration,
+    /// Probability of packet loss from 0 to 1.
+    pub loss: f64,
+}
+
+impl Impairments {
+    /// Returns `true` if the traffic is not impaired.
+    pub fn is_empty(&self) -> bool {
+        self.delay.is_zero() && self.jitter.is_zero() && self.loss == 0.0
+    }
+}
+
+impl NodeConfig {
+    /// Create the configuration of the node with the specified name.
+    ///
+    /// The address is assigned automatically.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the network interface address.
+    pub fn with_ifaddr(mut self, ifaddr: IpNet) -> Self {
+        self.ifaddr = ifaddr;
+        self
+    }
+
+    /// Set the impairments of the outgoing traffic.
+    pub fn with_impairments(mut self, impairments: Impairments) -> Self {
+        self.impairments = impairments;
+        self
+    }
+
+    /// Set the role.
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.role = role.into();
+        self
+    }
 }
 
 impl From<String> for NodeConfig {
@@ -1,10 +1,38 @@
 use std::fmt::Display;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use nix::fcntl::fcntl;
+use nix::fcntl::FcntlArg;
+use nix::sys::socket::SockProtocol;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::log_format;
+use crate::Error;
+use crate::Impairments;
+use crate::InterfaceStats;
 use crate::IpcClient;
 use crate::IpcMessage;
+use crate::Netlink;
 use crate::NodeConfig;
 
+/// Environment variable that contains the node's context.
+///
+/// It is set in each node process and is inherited by the processes that the node launches.
+/// See [`Context::from_env`].
+pub const CONTEXT_ENV: &str = "TESTNET_CONTEXT";
+
+/// Environment variable that contains the path of the node's unix socket.
+///
+/// Processes written in any language can connect to this socket
+/// to use barriers, broadcasts and the event log.
+/// The protocol is described in `PROTOCOL.md`.
+pub const SOCKET_ENV: &str = "TESTNET_SOCKET";
+
 /// Node execution context.
 ///
 /// Every node's `main` function receives its own instance of this context.
@@ -48,54 +76,139 @@ impl Context {
         &self.nodes[i]
     }
 
+    /// Current statistics of the node's network interface.
+    pub fn interface_stats(&self) -> Result<InterfaceStats, Error> {
+        Netlink::new(SockProtocol::NetlinkRoute)?.stats(&self.ifname)
+    }
+
+    /// Restore the context of the node that launched the current process.
+    ///
+    /// The node's IPC channel is inherited by the processes that the node launches,
+    /// and its file descriptors are passed via [`CONTEXT_ENV`] environment variable.
+    /// The variable is removed from the environment of the current process
+    /// so that the descriptors are claimed only once.
+    /// Only one process at a time should use the channel.
+    ///
+    /// All the fields of [`NodeConfig`] are restored.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptors from [`CONTEXT_ENV`] are owned by the returned context.
+    /// The caller must ensure that they were inherited from the node process
+    /// and are not used or closed by any other code in the current process,
+    /// and that no other thread reads or writes the environment concurrently.
+    pub unsafe fn from_env() -> Result<Self, Error> {
+        let value = std::env::var(CONTEXT_ENV)
+            .map_err(|e| Error::Ipc(format!("failed to read `{}`: {}", CONTEXT_ENV, e)))?;
+        let env: EnvContext = serde_json::from_str(&value)
+            .map_err(|e| Error::Ipc(format!("invalid `{}`: {}", CONTEXT_ENV, e)))?;
+        if env.node_index >= env.nodes.len() {
+            return Err(Error::Ipc(format!(
+                "invalid `{}`: node index {} is out of range",
+                CONTEXT_ENV, env.node_index
+            )));
+        }
+        for fd in [env.input_fd, env.output_fd] {
+            fcntl(fd, FcntlArg::F_GETFD)
+                .map_err(|e| Error::Ipc(format!("invalid `{}`: fd {}: {}", CONTEXT_ENV, fd, e)))?;
+        }
+        std::env::remove_var(CONTEXT_ENV);
+        // the descriptors are inherited from the node process
+        let (in_fd, out_fd) = (
+            OwnedFd::from_raw_fd(env.input_fd),
+            OwnedFd::from_raw_fd(env.output_fd),
+        );
+        Ok(Self {
+            node_index: env.node_index,
+            nodes: env.nodes.into_iter().map(Into::into).collect(),
+            ipc_client: IpcClient::new(in_fd, out_fd),
+            step_name: None,
+            step: 0,
+            ifname: env.ifname,
+        })
+    }
+
     /// Name the current step.
     ///
     /// Testnet will output the name of the step on failure.
     pub fn step(&mut self, name: impl Display) {
+        let name = name.to_string();
+        // The step is only used for diagnostics, hence the errors are ignored.
+        let _ = self
+            .ipc_client
+            .send(&IpcMessage::Step(name.clone()))
+            .and_then(|_| self.ipc_client.flush());
         self.step_name = Some(format!("\"{name}\""));
     }
 
     /// Broadcast from one node to every other one.
-    pub fn broadcast_one(&mut self) -> BroadcastOne {
+    pub fn broadcast_one(&mut self) -> BroadcastOne<'_> {
         BroadcastOne { context: self }
     }
 
     /// Broadcast data from each node to each node.
     ///
     /// The data is received by each node in a vec where each index corresponds to the node index.
-    pub fn broadcast_all(&mut self, data: Vec<u8>) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    pub fn broadcast_all(&mut self, data: Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
         self.next_step();
-        self.ipc_client.send(&IpcMessage::BroadcastAllSend(data))?;
-        self.ipc_client.flush()?;
-        self.ipc_client.fill_buf()?;
-        let response = self
-            .ipc_client
-            .recv()?
-            .ok_or_else(|| std::io::Error::other("no response"))?;
+        let response = self.ipc_client.call(&IpcMessage::BroadcastAllSend(data))?;
         let all_data = match response {
             IpcMessage::BroadcastAllRecv(payload) => payload,
-            _ => return Err(std::io::Error::other("invalid response")),
+            _ => return Err(Error::Ipc("invalid response".into())),
         };
         self.print_step();
         Ok(all_data)
     }
 
     /// Convenience wrapper around `broadcast_all` that receives a string instead of arbitrary data.
-    pub fn broadcast_all_string(&mut self, data: String) -> Result<Vec<String>, std::io::Error> {
+    pub fn broadcast_all_string(&mut self, data: String) -> Result<Vec<String>, Error> {
         self.broadcast_all(data.into())?
             .into_iter()
             .map(String::from_utf8)
             .collect::<Result<Vec<String>, _>>()
-            .map_err(std::io::Error::other)
+            .map_err(|e| Error::Io(std::io::Error::other(e)))
     }
 
-    fn next_step(&mut self) {
+    /// Store the value under the key in the network-wide key-value store.
+    ///
+    /// The previous value of the key is overwritten.
+    pub fn kv_put(&mut self, key: impl Into<String>, value: Vec<u8>) -> Result<(), Error> {
+        self.ipc_client
+            .send(&IpcMessage::KvPut(key.into(), value))?;
+        self.ipc_client.flush()?;
+        Ok(())
+    }
+
+    /// Get the value of the key from the network-wide key-value store.
+    ///
+    /// Blocks until some node puts the key.
+    pub fn kv_get(&mut self, key: impl Into<String>) -> Result<Vec<u8>, Error> {
+        let response = self.ipc_client.call(&IpcMessage::KvGet(key.into()))?;
+        match response {
+            IpcMessage::KvValue(value) => Ok(value),
+            _ => Err(Error::Ipc("invalid response".into())),
+        }
+    }
+
+    /// The value of [`CONTEXT_ENV`] environment variable.
+    pub(crate) fn to_env(&self) -> Result<String, Error> {
+        let env = EnvContext {
+            input_fd: self.ipc_client.input_raw_fd(),
+            output_fd: self.ipc_client.output_raw_fd(),
+            node_index: self.node_index,
+            ifname: self.ifname.clone(),
+            nodes: self.nodes.iter().map(Into::into).collect(),
+        };
+        serde_json::to_string(&env).map_err(|e| Error::Io(std::io::Error::other(e)))
+    }
+
+    pub(crate) fn next_step(&mut self) {
         self.step += 1;
     }
 
-    fn print_step(&mut self) {
+    pub(crate) fn print_step(&mut self) {
         if let Some(step) = self.step_name.take() {
-            log_format!("step {}: ok", step);
+            log_format!(info: "step {}: ok", step);
         }
     }
 }
@@ -108,6 +221,57 @@ impl Drop for Context {
     }
 }
 
+/// The value of [`CONTEXT_ENV`] environment variable.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvContext {
+    input_fd: RawFd,
+    output_fd: RawFd,
+    node_index: usize,
+    ifname: String,
+    nodes: Vec<EnvNode>,
+}
+
+/// Node configuration in [`CONTEXT_ENV`] environment variable.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvNode {
+    name: String,
+    ifaddr: IpNet,
+    delay: Duration,
+    jitter: Duration,
+    loss: f64,
+    role: String,
+}
+
+impl From<&NodeConfig> for EnvNode {
+    fn from(other: &NodeConfig) -> Self {
+        Self {
+            name: other.name.clone(),
+            ifaddr: other.ifaddr,
+            delay: other.impairments.delay,
+            jitter: other.impairments.jitter,
+            loss: other.impairments.loss,
+            role: other.role.clone(),
+        }
+    }
+}
+
+impl From<EnvNode> for NodeConfig {
+    fn from(other: EnvNode) -> Self {
+        Self {
+            name: other.name,
+            ifaddr: other.ifaddr,
+            impairments: Impairments {
+                delay: other.delay,
+                jitter: other.jitter,
+                loss: other.loss,
+            },
+            role: other.role,
+        }
+    }
+}
+
 /// Broadcast from one node to every other one.
 pub struct BroadcastOne<'a> {
     context: &'a mut Context,
@@ -120,25 +284,18 @@ impl<'a> BroadcastOne<'a> {
     /// that calls `recv` in the current step.
     /// Only one node per step can send the data, others should either call `recv` or `wait`.
     /// This function marks the the end of the current step.
-    pub fn send(self, data: Vec<u8>) -> Result<(), std::io::Error> {
+    pub fn send(self, data: Vec<u8>) -> Result<(), Error> {
         self.context.next_step();
-        self.context.ipc_client.send(&IpcMessage::Send(data))?;
-        self.context.ipc_client.flush()?;
-        self.context.ipc_client.fill_buf()?;
-        let response = self
-            .context
-            .ipc_client
-            .recv()?
-            .ok_or_else(|| std::io::Error::other("no response"))?;
+        let response = self.context.ipc_client.call(&IpcMessage::Send(data))?;
         if !matches!(response, IpcMessage::Wait) {
-            return Err(std::io::Error::other("invalid response"));
+            return Err(Error::Ipc("invalid response".into()));
         }
         self.context.print_step();
         Ok(())
     }
 
     /// Convenience wrapper around `send` that sends a string instead of arbitrary data.
-    pub fn send_string(self, data: String) -> Result<(), std::io::Error> {
+    pub fn send_string(self, data: String) -> Result<(), Error> {
         self.send(data.into())
     }
 
@@ -147,43 +304,34 @@ impl<'a> BroadcastOne<'a> {
     /// This function receives whatever data was sent by some node in the current step.
     /// Only one node per step can send the data, others should either call `recv` or `wait.
     /// This function marks the the end of the current step.
-    pub fn recv(&mut self) -> Result<Vec<u8>, std::io::Error> {
-        self.context.ipc_client.send(&IpcMessage::Receive)?;
-        self.context.ipc_client.flush()?;
-        self.context.ipc_client.fill_buf()?;
-        let response = self
-            .context
-            .ipc_client
-            .recv()?
-            .ok_or_else(|| std::io::Error::other("no response"))?;
-        match response {
-            IpcMessage::Send(data) => Ok(data),
-            _ => Err(std::io::Error::other("invalid response")),
-        }
+    pub fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.context.next_step();
+        let response = self.context.ipc_client.call(&IpcMessage::Receive)?;
+        let data = match response {
+            IpcMessage::Send(data) => data,
+            _ => return Err(Error::Ipc("invalid response".into())),
+        };
+        self.context.print_step();
+        Ok(data)
     }
 
     /// Convenience wrapper around `recv` that receives a string instead of arbitrary data.
-    pub fn recv_string(mut self) -> Result<String, std::io::Error> {
+    pub fn recv_string(mut self) -> Result<String, Error> {
         let data = self.recv()?;
-        String::from_utf8(data).map_err(std::io::Error::other)
+        String::from_utf8(data).map_err(|e| Error::Io(std::io::Error::other(e)))
     }
 
     /// Wait until broadcast is finished without sending or receiving any data.
     ///
     /// This function waits until the current step completes without sending or receiving any data.
     /// Only one node per step can send the data, others should either call `recv` or `wait.
-    pub fn wait(self) -> Result<(), std::io::Error> {
-        self.context.ipc_client.send(&IpcMessage::Wait)?;
-        self.context.ipc_client.flush()?;
-        self.context.ipc_client.fill_buf()?;
-        let response = self
-            .context
-            .ipc_client
-            .recv()?
-            .ok_or_else(|| std::io::Error::other("no response"))?;
+    pub fn wait(self) -> Result<(), Error> {
+        self.context.next_step();
+        let response = self.context.ipc_client.call(&IpcMessage::Wait)?;
         if !matches!(response, IpcMessage::Wait) {
-            return Err(std::io::Error::other("invalid response"));
+            return Err(Error::Ipc("invalid response".into()));
         }
+        self.context.print_step();
         Ok(())
     }
 }
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+/// Parse duration with `ns`, `us`, `ms` or `s` suffix.
+///
+/// The number can be fractional, e.g. `1.5s`.
+/// The command line tool and `#[testnet::test]` share this grammar
+/// (the procedural macros include this file).
+#[doc(hidden)]
+pub fn parse_duration(string: &str) -> Result<Duration, String> {
+    let i = string
+        .find(|ch: char| !ch.is_ascii_digit() && ch != '.')
+        .unwrap_or(string.len());
+    let (number, unit) = string.split_at(i);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}`", string))?;
+    let seconds = match unit.trim() {
+        "ns" => number / 1e9,
+        "us" => number / 1e6,
+        "ms" => number / 1e3,
+        "s" => number,
+        _ => return Err(format!("invalid duration unit in `{}`", string)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        assert_eq!(Ok(Duration::from_millis(500)), parse_duration("0.5s"));
+        assert_eq!(Ok(Duration::from_millis(10)), parse_duration("10ms"));
+        assert_eq!(Ok(Duration::from_micros(10)), parse_duration("10us"));
+        assert_eq!(Ok(Duration::from_nanos(10)), parse_duration("10ns"));
+    }
+
+    #[test]
+    fn invalid() {
+        // minutes are not supported
+        assert!(parse_duration("1m").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
@@ -1,22 +1,180 @@
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::time::Duration;
+
+use bincode::Decode;
+use bincode::Encode;
+use nix::errno::Errno;
+
+use crate::NodeFailure;
+
+/// Testnet error.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Failed to set up Linux namespaces, uid/gid mappings or mounts.
+    Namespace(std::io::Error),
+    /// Netlink request failed with the specified error number.
+    Netlink(Errno),
+    /// Inter-process communication protocol error.
+    Ipc(String),
+    /// The nodes did not finish in the specified time.
+    Timeout(Duration),
+    /// Some nodes failed.
+    NodeFailure(NodeFailure),
+    /// Linux namespaces are not available in this environment.
+    ///
+    /// Contains the reason.
+    Unsupported(String),
+    /// The network was not launched because Linux namespaces are not available
+    /// and [`NetOptions::skip_unsupported`](crate::NetOptions::skip_unsupported) is set.
+    ///
+    /// Contains the reason.
+    Skipped(String),
+    /// System call failed with the specified error number.
+    Os(Errno),
+    /// Input/output error.
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// Returns `true` if the network was skipped.
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, Self::Skipped(..))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Namespace(e) => write!(f, "namespace setup failed: {}", e),
+            Self::Netlink(errno) => write!(f, "netlink failed with error code {}", errno),
+            Self::Ipc(message) => write!(f, "ipc protocol error: {}", message),
+            Self::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+            Self::NodeFailure(failure) => Display::fmt(failure, f),
+            Self::Unsupported(reason) => write!(f, "namespaces are not supported: {}", reason),
+            Self::Skipped(reason) => write!(f, "skipped: {}", reason),
+            Self::Os(errno) => write!(f, "system call failed with error code {}", errno),
+            Self::Io(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Namespace(e) => Some(e),
+            Self::Netlink(errno) => Some(errno),
+            Self::NodeFailure(failure) => Some(failure),
+            Self::Os(errno) => Some(errno),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(other: std::io::Error) -> Self {
+        Self::Io(other)
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(other: Errno) -> Self {
+        Self::Os(other)
+    }
+}
+
+impl From<NodeFailure> for Error {
+    fn from(other: NodeFailure) -> Self {
+        Self::NodeFailure(other)
+    }
+}
+
+/// The error that is sent from the child process to the parent.
+#[derive(Encode, Decode, Debug)]
+pub(crate) enum RemoteError {
+    Namespace(String),
+    Netlink(i32),
+    Ipc(String),
+    Timeout(Duration),
+    Os(i32),
+    Other(String),
+}
+
+impl From<Error> for RemoteError {
+    fn from(other: Error) -> Self {
+        match other {
+            Error::Namespace(e) => Self::Namespace(e.to_string()),
+            Error::Netlink(errno) => Self::Netlink(errno as i32),
+            Error::Ipc(message) => Self::Ipc(message),
+            Error::Timeout(timeout) => Self::Timeout(timeout),
+            Error::Os(errno) => Self::Os(errno as i32),
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+impl From<RemoteError> for Error {
+    fn from(other: RemoteError) -> Self {
+        match other {
+            RemoteError::Namespace(message) => Self::Namespace(std::io::Error::other(message)),
+            RemoteError::Netlink(errno) => Self::Netlink(Errno::from_raw(errno)),
+            RemoteError::Ipc(message) => Self::Ipc(message),
+            RemoteError::Timeout(timeout) => Self::Timeout(timeout),
+            RemoteError::Os(errno) => Self::Os(Errno::from_raw(errno)),
+            RemoteError::Other(message) => Self::Io(std::io::Error::other(message)),
+        }
+    }
+}
+
+/// Print the message to stderr or emit `tracing` event if the feature is enabled.
+///
+/// The level is `error` unless specified as the first argument, e.g. `log_format!(warn: "...")`.
 macro_rules! log_format {
-    ($fmt_str:literal) => {
+    ($fmt_str:literal $(, $args:expr)*) => {
+        crate::log_format!(error: $fmt_str $(, $args)*)
+    };
+    ($level:ident: $fmt_str:literal $(, $args:expr)*) => {
         {
+            #[cfg(feature = "tracing")]
+            ::tracing::$level!($fmt_str $(, $args)*);
+            #[cfg(not(feature = "tracing"))]
             let _ = ::std::io::Write::write_all(
                 &mut ::std::io::stderr(),
-                format!(concat!($fmt_str, "\n")).as_bytes()
+                format!(concat!($fmt_str, "\n") $(, $args)*).as_bytes()
             );
         }
     };
-    ($fmt_str:literal, $($args:expr),*) => {
+}
+
+/// Emit `tracing` event if the feature is enabled.
+macro_rules! trace_event {
+    ($level:ident, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($args)*);
+    };
+}
+
+/// Enter `tracing` span if the feature is enabled.
+///
+/// The span is exited when the returned guard is dropped.
+macro_rules! trace_span {
+    ($level:ident, $($args:tt)*) => {
         {
-            let _ = ::std::io::Write::write_all(
-                &mut ::std::io::stderr(),
-                format!(concat!($fmt_str, "\n"), $($args),*).as_bytes()
-            );
+            #[cfg(feature = "tracing")]
+            let guard = ::tracing::span!(::tracing::Level::$level, $($args)*).entered();
+            #[cfg(not(feature = "tracing"))]
+            let guard = crate::NoSpan;
+            guard
         }
     };
 }
 
+/// The guard that is returned by `trace_span!` when `tracing` feature is disabled.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
 macro_rules! format_error {
     ($($args:expr),*) => {
         ::std::io::Error::new(::std::io::ErrorKind::Other, format!($($args),*))
@@ -25,3 +183,5 @@ macro_rules! format_error {
 
 pub(crate) use format_error;
 pub(crate) use log_format;
+pub(crate) use trace_event;
+pub(crate) use trace_span;
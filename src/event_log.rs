@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::log_format;
+use crate::Trace;
+
+/// Writes the events of the run as JSON Lines and collects the trace.
+///
+/// Each line is a JSON object with the time in seconds since the network start
+/// and the event's fields.
+pub(crate) struct EventLog {
+    writer: Option<LineWriter<File>>,
+    trace: Option<Trace>,
+    start: Instant,
+}
+
+impl EventLog {
+    pub(crate) fn new(
+        path: Option<&Path>,
+        trace_path: Option<&Path>,
+        start: Instant,
+    ) -> Result<Self, std::io::Error> {
+        let writer = match path {
+            Some(path) => Some(LineWriter::new(File::create(path)?)),
+            None => None,
+        };
+        Ok(Self {
+            writer,
+            trace: trace_path.map(|path| Trace::new(path.into())),
+            start,
+        })
+    }
+
+    /// Returns `true` if the events are written anywhere.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Write the event.
+    ///
+    /// The log is disabled on the first error to not fail the run.
+    pub(crate) fn write(&mut self, event: LogEvent<'_>) {
+        let time = self.start.elapsed().as_secs_f64();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.on_event(time, &event);
+        }
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let record = Record { time, event };
+        let result = serde_json::to_writer(&mut *writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"));
+        if let Err(e) = result {
+            log_format!("failed to write the event log: {}", e);
+            self.writer = None;
+        }
+    }
+
+    /// Write the trace file.
+    pub(crate) fn finish(&mut self) -> Result<(), std::io::Error> {
+        match self.trace.take() {
+            Some(trace) => trace.write(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: LogEvent<'a>,
+}
+
+/// An event of the run.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum LogEvent<'a> {
+    /// The node process was launched.
+    NodeSpawned {
+        node: usize,
+        name: &'a str,
+        pid: i32,
+    },
+    /// The node process exited.
+    NodeExited { node: usize, status: String },
+    /// The node named the current step.
+    Step { node: usize, name: &'a str },
+    /// The node entered the collective operation.
+    CollectiveEnter {
+        node: usize,
+        operation: &'static str,
+        payload_size: usize,
+    },
+    /// The collective operation finished on all the nodes.
+    Collective {
+        operation: &'static str,
+        participants: Vec<usize>,
+        payload_sizes: Vec<usize>,
+    },
+    /// The node printed the line.
+    Output {
+        node: usize,
+        stream: &'static str,
+        line: &'a str,
+    },
+    /// The node's socket client sent the message.
+    Message { node: usize, message: &'a str },
+    /// The nodes did not finish in time.
+    Timeout,
+}
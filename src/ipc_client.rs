@@ -1,13 +1,11 @@
 use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::io::Write;
 use std::os::fd::AsRawFd;
 use std::os::fd::OwnedFd;
 use std::os::fd::RawFd;
 
-use bincode::error::DecodeError;
 use mio::unix::SourceFd;
 use mio::Interest;
 use mio::Poll;
@@ -17,61 +15,165 @@ use crate::IpcEncodeDecode;
 use crate::IpcMessage;
 
 pub(crate) struct IpcClient {
-    reader: BufReader<File>,
-    writer: BufWriter<File>,
+    reader: File,
+    writer: File,
+    /// Bytes that were read but not yet decoded.
+    input: Vec<u8>,
+    /// Bytes that were encoded but not yet written.
+    output: Vec<u8>,
+    writer_registered: bool,
 }
 
 impl IpcClient {
     pub(crate) fn new(in_fd: OwnedFd, out_fd: OwnedFd) -> Self {
         Self {
-            reader: BufReader::with_capacity(MAX_MESSAGE_SIZE, in_fd.into()),
-            writer: BufWriter::with_capacity(MAX_MESSAGE_SIZE, out_fd.into()),
+            reader: in_fd.into(),
+            writer: out_fd.into(),
+            input: Vec::with_capacity(READ_BUFFER_SIZE),
+            output: Vec::with_capacity(READ_BUFFER_SIZE),
+            writer_registered: false,
         }
     }
 
-    pub(crate) fn fill_buf(&mut self) -> Result<(), std::io::Error> {
-        self.reader.fill_buf()?;
-        Ok(())
+    /// Read the next chunk of data from the pipe.
+    ///
+    /// Returns `false` if the other end of the pipe was closed.
+    pub(crate) fn fill_buf(&mut self) -> Result<bool, std::io::Error> {
+        let old_len = self.input.len();
+        self.input.resize(old_len + READ_BUFFER_SIZE, 0_u8);
+        let ret = loop {
+            match self.reader.read(&mut self.input[old_len..]) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                other => break other,
+            }
+        };
+        match ret {
+            Ok(n) => {
+                self.input.truncate(old_len + n);
+                Ok(n != 0)
+            }
+            Err(e) => {
+                self.input.truncate(old_len);
+                Err(e)
+            }
+        }
+    }
+
+    /// Read everything that is available from the non-blocking pipe.
+    ///
+    /// Returns `false` if the other end of the pipe was closed.
+    pub(crate) fn fill_buf_nonblocking(&mut self) -> Result<bool, std::io::Error> {
+        loop {
+            match self.fill_buf() {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
     }
 
+    /// Write as much buffered data as possible.
+    ///
+    /// Returns `true` if all the data was written.
     pub(crate) fn flush(&mut self) -> Result<bool, std::io::Error> {
-        self.writer.flush()?;
-        Ok(self.writer.buffer().is_empty())
+        let mut offset = 0;
+        while offset != self.output.len() {
+            match self.writer.write(&self.output[offset..]) {
+                Ok(0) => {
+                    self.output.drain(..offset);
+                    return Err(ErrorKind::WriteZero.into());
+                }
+                Ok(n) => offset += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.output.drain(..offset);
+                    return Err(e);
+                }
+            }
+        }
+        self.output.drain(..offset);
+        Ok(self.output.is_empty())
     }
 
+    /// Decode the next message from the buffered data.
+    ///
+    /// Returns `None` if the whole message has not been received yet.
     pub(crate) fn recv(&mut self) -> Result<Option<IpcMessage>, std::io::Error> {
-        match IpcMessage::decode(&mut self.reader) {
-            Ok(message) => Ok(Some(message)),
-            Err(DecodeError::UnexpectedEnd { .. }) => Ok(None),
-            Err(e) => Err(std::io::Error::other(e)),
+        match IpcMessage::decode(&self.input)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?
+        {
+            Some((message, n)) => {
+                self.input.drain(..n);
+                Ok(Some(message))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read from the blocking pipe until the whole message is received.
+    pub(crate) fn recv_blocking(&mut self) -> Result<IpcMessage, std::io::Error> {
+        loop {
+            if let Some(message) = self.recv()? {
+                return Ok(message);
+            }
+            if !self.fill_buf()? {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "no response"));
+            }
         }
     }
 
     pub(crate) fn send(&mut self, message: &IpcMessage) -> Result<(), std::io::Error> {
         message
-            .encode(&mut self.writer)
+            .encode(&mut self.output)
             .map_err(std::io::Error::other)?;
         Ok(())
     }
 
+    /// Send the message via blocking pipe and wait for the response.
+    pub(crate) fn call(&mut self, message: &IpcMessage) -> Result<IpcMessage, std::io::Error> {
+        self.send(message)?;
+        self.flush()?;
+        self.recv_blocking()
+    }
+
+    /// Flush the data and wait for the writer to become writable if some data remains.
     pub(crate) fn send_finalize(
         &mut self,
         writer_token: Token,
         poll: &mut Poll,
     ) -> Result<(), std::io::Error> {
-        if !self.flush()? {
-            poll.registry().reregister(
-                &mut SourceFd(&self.writer.get_ref().as_raw_fd()),
+        if !self.flush()? && !self.writer_registered {
+            poll.registry().register(
+                &mut SourceFd(&self.output_raw_fd()),
                 writer_token,
                 Interest::WRITABLE,
             )?;
+            self.writer_registered = true;
+        }
+        Ok(())
+    }
+
+    /// Flush the data and stop waiting for the writer to become writable if no data remains.
+    pub(crate) fn on_writable(&mut self, poll: &mut Poll) -> Result<(), std::io::Error> {
+        if self.flush()? && self.writer_registered {
+            poll.registry()
+                .deregister(&mut SourceFd(&self.output_raw_fd()))?;
+            self.writer_registered = false;
         }
         Ok(())
     }
 
+    pub(crate) fn input_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+
     pub(crate) fn output_raw_fd(&self) -> RawFd {
-        self.writer.get_ref().as_raw_fd()
+        self.writer.as_raw_fd()
     }
 }
 
-pub(crate) const MAX_MESSAGE_SIZE: usize = 4096 * 16;
+/// Max. size of the message payload.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 64;
+const READ_BUFFER_SIZE: usize = 4096 * 16;
@@ -1,15 +1,16 @@
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Read;
-use std::io::Write;
+use std::fmt::Display;
+use std::fmt::Formatter;
 
 use bincode::decode_from_slice;
 use bincode::encode_into_std_write;
+use bincode::encode_to_vec;
 use bincode::error::DecodeError;
 use bincode::error::EncodeError;
 use bincode::Decode;
 use bincode::Encode;
 
+use crate::RemoteError;
+use crate::VethStats;
 use crate::MAX_MESSAGE_SIZE;
 
 #[derive(Decode, Encode, Debug)]
@@ -19,34 +20,133 @@ pub(crate) enum IpcMessage {
     Wait,
     BroadcastAllSend(BroadcastPayload),
     BroadcastAllRecv(Vec<BroadcastPayload>),
+    /// The value returned by the node's `main` function.
+    Return(Vec<u8>),
+    /// The name of the current step.
+    Step(String),
+    /// The node failed before its `main` function was called.
+    Failed(RemoteError),
+    /// Interface statistics right before the node exits.
+    Stats(VethStats),
+    /// Store the value under the key.
+    KvPut(String, Vec<u8>),
+    /// Request the value of the key.
+    KvGet(String),
+    /// The value of the requested key.
+    KvValue(Vec<u8>),
 }
 
 pub(crate) type BroadcastPayload = Vec<u8>;
 
+/// Length-prefixed message framing.
+///
+/// Each frame consists of the message length (32-bit little-endian integer)
+/// followed by the message itself.
 pub(crate) trait IpcEncodeDecode {
-    fn encode(&self, writer: &mut impl Write) -> Result<(), EncodeError>;
-    fn decode<R>(reader: &mut BufReader<R>) -> Result<Self, DecodeError>
+    /// Append the frame to the buffer.
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError>;
+
+    /// Decode the first frame from the buffer.
+    ///
+    /// Returns the message and the length of the frame
+    /// or `None` if the buffer does not contain the whole frame yet.
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, DecodeError>
     where
-        Self: Sized,
-        BufReader<R>: BufRead,
-        R: Read;
+        Self: Sized;
 }
 
 impl<T: Encode + Decode> IpcEncodeDecode for T {
-    fn encode(&self, writer: &mut impl Write) -> Result<(), EncodeError> {
-        encode_into_std_write(self, writer, bincode_config())?;
-        Ok(())
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let start = begin_frame(buf);
+        if let Err(e) = encode_into_std_write(self, buf, bincode_config()) {
+            buf.truncate(start);
+            return Err(e);
+        }
+        end_frame(buf, start).map_err(|_| EncodeError::Other("message is too large"))
     }
 
-    fn decode<R>(reader: &mut BufReader<R>) -> Result<Self, DecodeError>
-    where
-        BufReader<R>: BufRead,
-        R: Read,
-    {
-        let (object, n): (Self, usize) = decode_from_slice(reader.buffer(), bincode_config())?;
-        reader.consume(n);
-        Ok(object)
+    fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, DecodeError> {
+        let Some((payload, frame_len)) =
+            decode_frame(buf).map_err(|_| DecodeError::LimitExceeded)?
+        else {
+            return Ok(None);
+        };
+        let (object, n): (Self, usize) = decode_from_slice(payload, bincode_config())?;
+        if n != payload.len() {
+            return Err(DecodeError::OtherString(format!(
+                "frame length mismatch: expected {}, actual {n}",
+                payload.len()
+            )));
+        }
+        Ok(Some((object, frame_len)))
+    }
+}
+
+/// Append the header of the new frame to the buffer.
+///
+/// Returns the offset of the frame that is passed to [`end_frame`]
+/// after the payload has been appended.
+pub(crate) fn begin_frame(buf: &mut Vec<u8>) -> usize {
+    let start = buf.len();
+    buf.extend_from_slice(&[0_u8; FRAME_HEADER_SIZE]);
+    start
+}
+
+/// Write the length of the payload to the header of the frame that starts at `start`.
+///
+/// The frame is removed from the buffer if the payload is too large.
+pub(crate) fn end_frame(buf: &mut Vec<u8>, start: usize) -> Result<(), FrameTooLarge> {
+    let len = buf.len() - start - FRAME_HEADER_SIZE;
+    if len > MAX_MESSAGE_SIZE {
+        buf.truncate(start);
+        return Err(FrameTooLarge);
+    }
+    buf[start..(start + FRAME_HEADER_SIZE)].copy_from_slice(&(len as u32).to_le_bytes());
+    Ok(())
+}
+
+/// Find the first frame in the buffer.
+///
+/// Returns the payload and the length of the frame
+/// or `None` if the buffer does not contain the whole frame yet.
+pub(crate) fn decode_frame(buf: &[u8]) -> Result<Option<(&[u8], usize)>, FrameTooLarge> {
+    let Some(header) = buf.get(..FRAME_HEADER_SIZE) else {
+        return Ok(None);
+    };
+    let mut len = [0_u8; FRAME_HEADER_SIZE];
+    len.copy_from_slice(header);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(FrameTooLarge);
     }
+    let frame_len = FRAME_HEADER_SIZE + len;
+    let Some(payload) = buf.get(FRAME_HEADER_SIZE..frame_len) else {
+        return Ok(None);
+    };
+    Ok(Some((payload, frame_len)))
+}
+
+/// The payload of the frame is larger than [`MAX_MESSAGE_SIZE`].
+#[derive(Debug)]
+pub(crate) struct FrameTooLarge;
+
+impl Display for FrameTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("message is too large")
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// Encode the value returned by the node's `main` function.
+pub(crate) fn encode_value<T: Encode>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    encode_to_vec(value, bincode_config())
+}
+
+/// Decode the value returned by the node's `main` function.
+pub(crate) fn decode_value<T: Decode>(data: &[u8]) -> Result<T, DecodeError> {
+    let (object, _): (T, usize) = decode_from_slice(data, bincode_config())?;
+    Ok(object)
 }
 
 const fn bincode_config() -> bincode::config::Configuration<
@@ -59,3 +159,5 @@ const fn bincode_config() -> bincode::config::Configuration<
         .with_fixed_int_encoding()
         .with_limit::<MAX_MESSAGE_SIZE>()
 }
+
+const FRAME_HEADER_SIZE: usize = 4;
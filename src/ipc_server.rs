@@ -1,13 +1,13 @@
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Write;
+use std::io::ErrorKind;
 use std::os::fd::AsRawFd;
 use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
+use std::time::Duration;
+use std::time::Instant;
 
 use mio::event::Event;
+use mio::net::UnixListener;
 use mio::unix::SourceFd;
 use mio::Events;
 use mio::Interest;
@@ -22,60 +22,123 @@ use nix::fcntl::OFlag;
 use nix::sys::wait::waitid;
 use nix::sys::wait::WaitPidFlag;
 use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
 
 use crate::format_error;
 use crate::log_format;
+use crate::trace_event;
+use crate::EventLog;
 use crate::IpcClient;
+use crate::IpcMessage;
 use crate::IpcStateMachine;
+use crate::LogEvent;
+use crate::NodeOutput;
+use crate::OutputConfig;
+use crate::OutputFiles;
+use crate::OutputStream;
+use crate::Pending;
+use crate::Prefix;
+use crate::RemoteError;
+use crate::SocketAction;
+use crate::SocketClient;
+use crate::VethStats;
 
 pub(crate) struct IpcServer {
     poll: Poll,
     clients: Vec<IpcClient>,
     pid_fds: Vec<PidFd>,
-    output_readers: Vec<OutputReader>,
+    outputs: Vec<NodeOutput>,
+    listeners: Vec<UnixListener>,
+    /// The clients that are connected to the nodes' unix sockets.
+    sockets: Vec<Option<SocketClient>>,
+    /// The requests of the disconnected socket clients that were not answered yet.
+    abandoned: Vec<Option<Pending>>,
     state: IpcStateMachine,
     finished: HashSet<usize>,
+    /// The information that the nodes sent about themselves.
+    node_states: Vec<NodeState>,
+    event_log: EventLog,
 }
 
 impl IpcServer {
+    /// Create the server for the nodes' file descriptors.
+    ///
+    /// Output timestamps are relative to `start`.
     pub(crate) fn new(
-        fds: Vec<(OwnedFd, OwnedFd, PidFd, OwnedFd, String)>,
+        fds: Vec<NodeFds>,
+        output: &OutputConfig,
+        start: Instant,
+        mut event_log: EventLog,
     ) -> Result<Self, std::io::Error> {
         let poll = Poll::new()?;
         let mut clients = Vec::with_capacity(fds.len());
         let mut pid_fds = Vec::with_capacity(fds.len());
-        let mut output_readers = Vec::with_capacity(fds.len());
-        for (i, (in_fd, out_fd, pid_fd, output_fd, node_name)) in fds.into_iter().enumerate() {
-            fcntl(in_fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
-            fcntl(out_fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
-            fcntl(output_fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let mut outputs = Vec::with_capacity(fds.len());
+        let mut listeners = Vec::with_capacity(fds.len());
+        let mut files = OutputFiles::default();
+        for (i, fds) in fds.into_iter().enumerate() {
+            fcntl(fds.ipc_in.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            fcntl(
+                fds.ipc_out.as_raw_fd(),
+                FcntlArg::F_SETFL(OFlag::O_NONBLOCK),
+            )?;
+            fcntl(fds.stdout.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            fcntl(fds.stderr.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            fds.socket.set_nonblocking(true)?;
+            let mut listener = UnixListener::from_std(fds.socket);
+            poll.registry()
+                .register(&mut listener, listener_token(i), Interest::READABLE)?;
+            listeners.push(listener);
             poll.registry().register(
-                &mut SourceFd(&in_fd.as_raw_fd()),
+                &mut SourceFd(&fds.ipc_in.as_raw_fd()),
                 fd_in_token(i),
                 Interest::READABLE,
             )?;
             poll.registry().register(
-                &mut SourceFd(&pid_fd.as_raw_fd()),
+                &mut SourceFd(&fds.pid.as_raw_fd()),
                 pid_fd_token(i),
                 Interest::READABLE,
             )?;
             poll.registry().register(
-                &mut SourceFd(&output_fd.as_raw_fd()),
-                output_fd_token(i),
+                &mut SourceFd(&fds.stdout.as_raw_fd()),
+                stdout_fd_token(i),
+                Interest::READABLE,
+            )?;
+            poll.registry().register(
+                &mut SourceFd(&fds.stderr.as_raw_fd()),
+                stderr_fd_token(i),
                 Interest::READABLE,
             )?;
-            clients.push(IpcClient::new(in_fd, out_fd));
-            pid_fds.push(pid_fd);
-            output_readers.push(OutputReader::new(output_fd, node_name));
+            event_log.write(LogEvent::NodeSpawned {
+                node: i,
+                name: &fds.name,
+                pid: fds.process_id.as_raw(),
+            });
+            clients.push(IpcClient::new(fds.ipc_in, fds.ipc_out));
+            pid_fds.push(fds.pid);
+            let prefix = Prefix::new(
+                fds.name,
+                i,
+                output.colors,
+                output.timestamps.then_some(start),
+            );
+            outputs.push(NodeOutput::new(
+                fds.stdout, fds.stderr, i, prefix, output, &mut files,
+            )?);
         }
         let num_nodes = clients.len();
         Ok(Self {
             poll,
             clients,
             pid_fds,
-            output_readers,
+            outputs,
+            sockets: (0..num_nodes).map(|_| None).collect(),
+            abandoned: (0..num_nodes).map(|_| None).collect(),
+            listeners,
             state: IpcStateMachine::new(num_nodes),
             finished: Default::default(),
+            node_states: (0..num_nodes).map(|_| NodeState::default()).collect(),
+            event_log,
         })
     }
 
@@ -83,44 +146,111 @@ impl IpcServer {
         Waker::new(self.poll.registry(), WAKE_TOKEN)
     }
 
-    pub(crate) fn run(&mut self) -> Result<(), std::io::Error> {
+    /// Serve the nodes until they finish.
+    ///
+    /// Stops early if some node failed or the nodes did not finish in the specified time.
+    /// Returns an error if the server itself failed or some node broke the protocol.
+    pub(crate) fn run(&mut self, timeout: Option<Duration>) -> Result<ServerStop, std::io::Error> {
         let mut events = Events::with_capacity(self.clients.len());
         let n = self.clients.len();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         while self.finished.len() != n {
             events.clear();
-            match self.poll.poll(&mut events, None) {
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) => Some(timeout),
+                    None => return Ok(ServerStop::TimedOut),
+                },
+                None => None,
+            };
+            match self.poll.poll(&mut events, timeout) {
                 Ok(()) => Ok(()),
                 Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
                 other => other,
             }?;
             for event in events.iter() {
                 let ret = match event.token() {
-                    WAKE_TOKEN => return Ok(()),
+                    WAKE_TOKEN => return Ok(ServerStop::Finished),
                     token @ Token(i) if (0..(NUM_FDS * n)).contains(&i) => {
                         let i = token_to_client_index(token);
                         match FdKind::new(token) {
-                            FdKind::In | FdKind::Out => self.on_event(event, token, i),
+                            FdKind::In => self.on_readable(event, i),
+                            FdKind::Out => self.on_writable(i),
                             FdKind::Pid => {
                                 self.handle_finished(event, i);
                                 if self.process_failed(i)? {
-                                    return Err(format_error!("node {i} failed"));
+                                    return Ok(ServerStop::NodeFailed(i));
                                 }
                                 Ok(())
                             }
-                            FdKind::ProcessOutput => {
+                            FdKind::Stdout => {
+                                self.handle_finished(event, i);
+                                self.on_process_output(event, i, OutputStream::Stdout)?;
+                                Ok(())
+                            }
+                            FdKind::Stderr => {
                                 self.handle_finished(event, i);
-                                self.on_process_output(event, i)?;
+                                self.on_process_output(event, i, OutputStream::Stderr)?;
+                                Ok(())
+                            }
+                            FdKind::Listener => self.on_connect(i),
+                            FdKind::Socket => {
+                                self.on_socket_event(event, i);
                                 Ok(())
                             }
                         }
                     }
                     Token(i) => Err(format_error!("unknown event {i}")),
                 };
-                if let Err(e) = ret {
-                    log_format!("ipc server error: {}", e);
+                match ret {
+                    // the stream can not be resynchronized after a malformed message
+                    Err(e) if e.kind() == ErrorKind::InvalidData => return Err(e),
+                    Err(e) => log_format!("ipc server error: {}", e),
+                    Ok(()) => {}
                 }
             }
         }
+        Ok(ServerStop::Finished)
+    }
+
+    /// Write the event to the event log.
+    pub(crate) fn log_event(&mut self, event: LogEvent<'_>) {
+        self.event_log.write(event);
+    }
+
+    /// Write the files that are built from the whole run.
+    pub(crate) fn finish_event_log(&mut self) -> Result<(), std::io::Error> {
+        self.event_log.finish()
+    }
+
+    /// Take the information that the nodes sent about themselves.
+    pub(crate) fn take_node_states(&mut self) -> Vec<NodeState> {
+        std::mem::take(&mut self.node_states)
+    }
+
+    /// Read the output that remains after the nodes exited and return the last lines of each node's output.
+    pub(crate) fn take_output(&mut self) -> Result<Vec<Vec<String>>, std::io::Error> {
+        let mut output = Vec::with_capacity(self.outputs.len());
+        for node_output in self.outputs.iter_mut() {
+            node_output.read_remaining(&mut self.event_log)?;
+            output.push(node_output.take_buffer());
+        }
+        Ok(output)
+    }
+
+    /// Receive the messages that were sent right before the nodes exited.
+    pub(crate) fn receive_remaining(&mut self) -> Result<(), std::io::Error> {
+        for (i, (client, state)) in self
+            .clients
+            .iter_mut()
+            .zip(self.node_states.iter_mut())
+            .enumerate()
+        {
+            client.fill_buf_nonblocking()?;
+            while let Some(message) = client.recv()? {
+                state.on_message(message, i, &mut self.event_log);
+            }
+        }
         Ok(())
     }
 
@@ -130,43 +260,133 @@ impl IpcServer {
         }
     }
 
-    fn on_event(
-        &mut self,
-        event: &Event,
-        writer_token: Token,
-        i: usize,
-    ) -> Result<(), std::io::Error> {
-        let mut interest: Option<Interest> = None;
+    fn on_readable(&mut self, event: &Event, i: usize) -> Result<(), std::io::Error> {
         if event.is_readable() {
-            self.clients[i].fill_buf()?;
+            self.clients[i].fill_buf_nonblocking()?;
             while let Some(message) = self.clients[i].recv()? {
-                self.state
-                    .on_message(message, i, &mut self.clients, writer_token, &mut self.poll)
-                    .map_err(std::io::Error::other)?;
+                let Some(message) = self.node_states[i].on_message(message, i, &mut self.event_log)
+                else {
+                    continue;
+                };
+                let responses = self.state.on_message(message, i, &mut self.event_log)?;
+                self.respond(responses)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the responses of the state machine to the nodes.
+    ///
+    /// The response goes to the socket client if it waits for it, and to the node's pipe otherwise.
+    /// The response to the socket client that has disconnected is dropped.
+    fn respond(&mut self, responses: Vec<(usize, IpcMessage)>) -> Result<(), std::io::Error> {
+        for (i, message) in responses.into_iter() {
+            if self.abandoned[i]
+                .as_ref()
+                .is_some_and(|pending| pending.expects(&message))
+            {
+                self.abandoned[i] = None;
+                continue;
             }
-            if !self.clients[i].flush()? {
-                interest = Some(Interest::WRITABLE);
+            match self.sockets[i].as_mut() {
+                Some(socket) if socket.expects(&message) => {
+                    if let Err(e) = socket.respond(message) {
+                        self.close_socket(i, e);
+                    }
+                }
+                _ => {
+                    self.clients[i].send(&message)?;
+                    self.clients[i].send_finalize(fd_out_token(i), &mut self.poll)?;
+                }
             }
         }
-        let client = &mut self.clients[i];
-        if event.is_writable() && client.flush()? {
-            interest = Some(Interest::READABLE);
+        Ok(())
+    }
+
+    fn on_connect(&mut self, i: usize) -> Result<(), std::io::Error> {
+        loop {
+            let mut stream = match self.listeners[i].accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if self.sockets[i].is_some() {
+                let mut socket = SocketClient::new(stream);
+                let _ = socket.send_error("another client is connected".into());
+                continue;
+            }
+            self.poll.registry().register(
+                &mut stream,
+                socket_token(i),
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            self.sockets[i] = Some(SocketClient::new(stream));
         }
-        match interest {
-            Some(Interest::READABLE) => self
-                .poll
-                .registry()
-                .deregister(&mut SourceFd(&client.output_raw_fd()))?,
-            Some(Interest::WRITABLE) => {
-                self.poll.registry().reregister(
-                    &mut SourceFd(&client.output_raw_fd()),
-                    writer_token,
-                    Interest::WRITABLE,
-                )?;
+    }
+
+    fn on_socket_event(&mut self, event: &Event, i: usize) {
+        match self.serve_socket(event, i) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.remove_socket(i);
             }
-            _ => {}
+            Err(e) => self.close_socket(i, e),
         }
-        Ok(())
+    }
+
+    /// Returns `false` if the client closed the connection.
+    fn serve_socket(&mut self, event: &Event, i: usize) -> Result<bool, std::io::Error> {
+        let Some(socket) = self.sockets[i].as_mut() else {
+            return Ok(false);
+        };
+        if event.is_writable() {
+            socket.flush()?;
+        }
+        let open = socket.fill_buf()?;
+        while let Some(action) = match self.sockets[i].as_mut() {
+            Some(socket) => socket.recv()?,
+            None => None,
+        } {
+            match action {
+                SocketAction::Forward(message) => {
+                    let Some(message) =
+                        self.node_states[i].on_message(message, i, &mut self.event_log)
+                    else {
+                        continue;
+                    };
+                    let responses = self.state.on_message(message, i, &mut self.event_log)?;
+                    self.respond(responses)?;
+                }
+                SocketAction::Log(message) => self.event_log.write(LogEvent::Message {
+                    node: i,
+                    message: &message,
+                }),
+                SocketAction::None => {}
+            }
+        }
+        Ok(open)
+    }
+
+    /// Send the error to the socket client and close the connection.
+    fn close_socket(&mut self, i: usize, error: std::io::Error) {
+        if let Some(mut socket) = self.remove_socket(i) {
+            let _ = socket.send_error(error.to_string());
+        }
+    }
+
+    /// Stop serving the socket client and remember its unanswered request.
+    fn remove_socket(&mut self, i: usize) -> Option<SocketClient> {
+        let mut socket = self.sockets[i].take()?;
+        let _ = self.poll.registry().deregister(socket.stream());
+        if let Some(pending) = socket.take_pending() {
+            self.abandoned[i] = Some(pending);
+        }
+        Some(socket)
+    }
+
+    fn on_writable(&mut self, i: usize) -> Result<(), std::io::Error> {
+        self.clients[i].on_writable(&mut self.poll)
     }
 
     fn process_failed(&mut self, i: usize) -> Result<bool, std::io::Error> {
@@ -194,84 +414,96 @@ impl IpcServer {
         }
     }
 
-    fn on_process_output(&mut self, event: &Event, i: usize) -> Result<(), std::io::Error> {
-        if event.is_readable() {
-            self.output_readers[i].print_lines()?;
-        }
-        if event.is_error() || event.is_read_closed() || event.is_write_closed() {
-            self.output_readers[i].print_lines()?;
-            self.output_readers[i].print_remaining()?;
+    fn on_process_output(
+        &mut self,
+        event: &Event,
+        i: usize,
+        stream: OutputStream,
+    ) -> Result<(), std::io::Error> {
+        if event.is_readable() || event.is_error() || event.is_read_closed() {
+            self.outputs[i].read_lines(stream, &mut self.event_log)?;
         }
         Ok(())
     }
 }
 
-struct OutputReader {
-    reader: BufReader<File>,
-    line: LineBuffer,
+/// The file descriptors that connect the switch to the node.
+/// Why [`IpcServer::run`] stopped serving the nodes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ServerStop {
+    /// All the nodes finished.
+    Finished,
+    /// The node with the specified index failed.
+    NodeFailed(usize),
+    /// The nodes did not finish in time.
+    TimedOut,
 }
 
-impl OutputReader {
-    pub(crate) fn new(in_fd: OwnedFd, name: String) -> Self {
-        Self {
-            reader: BufReader::with_capacity(OUTPUT_BUFFER_SIZE, in_fd.into()),
-            line: LineBuffer::new(format!("{name}: ")),
-        }
-    }
-
-    pub(crate) fn print_lines(&mut self) -> Result<(), std::io::Error> {
-        let mut buf = self.reader.fill_buf()?;
-        let buf_len = buf.len();
-        while let Some(mut i) = buf.iter().position(|ch| *ch == b'\n') {
-            i += 1;
-            self.line.append(&buf[..i]);
-            self.line.print()?;
-            buf = &buf[i..];
-        }
-        if !buf.is_empty() {
-            self.line.append(buf);
-        }
-        self.reader.consume(buf_len);
-        Ok(())
-    }
-
-    pub(crate) fn print_remaining(&mut self) -> Result<(), std::io::Error> {
-        if !self.line.is_empty() {
-            self.line.append("⏎\n".as_bytes());
-            self.line.print()?;
-        }
-        Ok(())
-    }
+pub(crate) struct NodeFds {
+    pub(crate) ipc_in: OwnedFd,
+    pub(crate) ipc_out: OwnedFd,
+    pub(crate) pid: PidFd,
+    pub(crate) process_id: Pid,
+    pub(crate) stdout: OwnedFd,
+    pub(crate) stderr: OwnedFd,
+    pub(crate) socket: std::os::unix::net::UnixListener,
+    pub(crate) name: String,
 }
 
-struct LineBuffer {
-    line: Vec<u8>,
-    prefix: String,
+/// The information that the node sent about itself.
+#[derive(Default)]
+pub(crate) struct NodeState {
+    /// The value returned by the node's `main` function.
+    pub(crate) value: Option<Vec<u8>>,
+    /// The number of collective operations that the node has entered.
+    pub(crate) step: usize,
+    /// The name of the last step.
+    pub(crate) step_name: Option<String>,
+    /// The error that occurred before the node's `main` function was called.
+    pub(crate) error: Option<RemoteError>,
+    /// Interface statistics right before the node exited.
+    pub(crate) interface_stats: Option<VethStats>,
 }
 
-impl LineBuffer {
-    fn new(prefix: String) -> Self {
-        Self {
-            line: Vec::new(),
-            prefix,
-        }
-    }
-
-    fn is_empty(&self) -> bool {
-        self.line.is_empty()
-    }
-
-    fn append(&mut self, buf: &[u8]) {
-        if self.line.is_empty() {
-            self.line.extend_from_slice(self.prefix.as_bytes());
+impl NodeState {
+    /// Update the state and return the message if it is a collective operation.
+    fn on_message(
+        &mut self,
+        message: IpcMessage,
+        i: usize,
+        event_log: &mut EventLog,
+    ) -> Option<IpcMessage> {
+        match message {
+            IpcMessage::Return(value) => {
+                trace_event!(debug, node = i, size = value.len(), "node returned");
+                self.value = Some(value);
+            }
+            IpcMessage::Step(name) => {
+                trace_event!(info, node = i, step = self.step, %name, "node started step");
+                event_log.write(LogEvent::Step {
+                    node: i,
+                    name: &name,
+                });
+                self.step_name = Some(name);
+            }
+            IpcMessage::Failed(error) => {
+                trace_event!(error, node = i, ?error, "node setup failed");
+                self.error = Some(error);
+            }
+            IpcMessage::Stats(stats) => self.interface_stats = Some(stats),
+            message @ (IpcMessage::KvPut(..) | IpcMessage::KvGet(..)) => return Some(message),
+            message => {
+                self.step += 1;
+                trace_event!(
+                    trace,
+                    node = i,
+                    step = self.step,
+                    "node reached collective step"
+                );
+                return Some(message);
+            }
         }
-        self.line.extend_from_slice(buf);
-    }
-
-    fn print(&mut self) -> Result<(), std::io::Error> {
-        std::io::stderr().write_all(&self.line)?;
-        self.line.clear();
-        Ok(())
+        None
     }
 }
 
@@ -279,14 +511,30 @@ fn fd_in_token(i: usize) -> Token {
     Token(NUM_FDS * i)
 }
 
+pub(crate) fn fd_out_token(i: usize) -> Token {
+    Token(NUM_FDS * i + 1)
+}
+
 fn pid_fd_token(i: usize) -> Token {
     Token(NUM_FDS * i + 2)
 }
 
-fn output_fd_token(i: usize) -> Token {
+fn stdout_fd_token(i: usize) -> Token {
     Token(NUM_FDS * i + 3)
 }
 
+fn stderr_fd_token(i: usize) -> Token {
+    Token(NUM_FDS * i + 4)
+}
+
+fn listener_token(i: usize) -> Token {
+    Token(NUM_FDS * i + 5)
+}
+
+fn socket_token(i: usize) -> Token {
+    Token(NUM_FDS * i + 6)
+}
+
 fn token_to_client_index(token: Token) -> usize {
     token.0 / NUM_FDS
 }
@@ -295,7 +543,10 @@ enum FdKind {
     In,
     Out,
     Pid,
-    ProcessOutput,
+    Stdout,
+    Stderr,
+    Listener,
+    Socket,
 }
 
 impl FdKind {
@@ -304,7 +555,10 @@ impl FdKind {
             0 => Self::In,
             1 => Self::Out,
             2 => Self::Pid,
-            _ => Self::ProcessOutput,
+            3 => Self::Stdout,
+            4 => Self::Stderr,
+            5 => Self::Listener,
+            _ => Self::Socket,
         }
     }
 }
@@ -318,5 +572,4 @@ fn status_is_failure(status: WaitStatus) -> bool {
 }
 
 const WAKE_TOKEN: Token = Token(usize::MAX);
-const NUM_FDS: usize = 4;
-pub(crate) const OUTPUT_BUFFER_SIZE: usize = 4096 * 16;
+const NUM_FDS: usize = 7;
@@ -0,0 +1,279 @@
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use mio::net::UnixStream;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::begin_frame;
+use crate::decode_frame;
+use crate::end_frame;
+use crate::IpcMessage;
+
+/// The client that is connected to the node's unix socket.
+///
+/// The client speaks JSON protocol that is described in `PROTOCOL.md`.
+/// The requests are translated to [`IpcMessage`]s and the responses are translated back.
+pub(crate) struct SocketClient {
+    stream: UnixStream,
+    /// Bytes that were read but not yet decoded.
+    input: Vec<u8>,
+    /// Bytes that were encoded but not yet written.
+    output: Vec<u8>,
+    /// The protocol version that the client requested.
+    version: Option<u32>,
+    /// The request that waits for the response from the state machine.
+    pending: Option<Pending>,
+}
+
+impl SocketClient {
+    pub(crate) fn new(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+            version: None,
+            pending: None,
+        }
+    }
+
+    pub(crate) fn stream(&mut self) -> &mut UnixStream {
+        &mut self.stream
+    }
+
+    /// Read everything that is available from the socket.
+    ///
+    /// Returns `false` if the client closed the connection.
+    pub(crate) fn fill_buf(&mut self) -> Result<bool, std::io::Error> {
+        let mut buf = [0_u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Decode the next request and handle the requests that do not need the state machine.
+    ///
+    /// Returns `None` if the whole request has not been received yet.
+    pub(crate) fn recv(&mut self) -> Result<Option<SocketAction>, std::io::Error> {
+        let Some((payload, frame_len)) =
+            decode_frame(&self.input).map_err(std::io::Error::other)?
+        else {
+            return Ok(None);
+        };
+        let request: Result<SocketRequest, _> = serde_json::from_slice(payload);
+        self.input.drain(..frame_len);
+        let request = request.map_err(std::io::Error::other)?;
+        let request = match (self.version, request) {
+            (None, SocketRequest::Hello { version }) if version == PROTOCOL_VERSION => {
+                self.version = Some(version);
+                self.send(&SocketResponse::Hello {
+                    version: PROTOCOL_VERSION,
+                })?;
+                return Ok(Some(SocketAction::None));
+            }
+            (None, SocketRequest::Hello { version }) => {
+                return Err(std::io::Error::other(format!(
+                    "unsupported protocol version {}, expected {}",
+                    version, PROTOCOL_VERSION
+                )));
+            }
+            (None, _) => return Err(std::io::Error::other("`hello` is expected")),
+            (Some(_), SocketRequest::Hello { .. }) => {
+                return Err(std::io::Error::other("duplicate `hello`"));
+            }
+            (Some(_), request) => request,
+        };
+        if self.pending.is_some() {
+            return Err(std::io::Error::other("another request is in progress"));
+        }
+        let (message, pending) = match request {
+            SocketRequest::Hello { .. } => return Ok(Some(SocketAction::None)),
+            SocketRequest::Log { message } => {
+                self.send(&SocketResponse::Ok)?;
+                return Ok(Some(SocketAction::Log(message)));
+            }
+            SocketRequest::Step { name } => {
+                self.send(&SocketResponse::Ok)?;
+                return Ok(Some(SocketAction::Forward(IpcMessage::Step(name))));
+            }
+            SocketRequest::KvPut { key, value } => {
+                self.send(&SocketResponse::Ok)?;
+                return Ok(Some(SocketAction::Forward(IpcMessage::KvPut(
+                    key,
+                    value.into(),
+                ))));
+            }
+            SocketRequest::KvGet { key } => (IpcMessage::KvGet(key), Pending::KvGet),
+            SocketRequest::Barrier { name } => (
+                IpcMessage::BroadcastAllSend(name.clone().into()),
+                Pending::Barrier(name),
+            ),
+            SocketRequest::BroadcastAll { data } => (
+                IpcMessage::BroadcastAllSend(data.into()),
+                Pending::BroadcastAll,
+            ),
+            SocketRequest::BroadcastSend { data } => (IpcMessage::Send(data.into()), Pending::Wait),
+            SocketRequest::BroadcastReceive => (IpcMessage::Receive, Pending::Receive),
+            SocketRequest::BroadcastWait => (IpcMessage::Wait, Pending::Wait),
+        };
+        self.pending = Some(pending);
+        Ok(Some(SocketAction::Forward(message)))
+    }
+
+    /// Returns `true` if the client waits for this response.
+    pub(crate) fn expects(&self, message: &IpcMessage) -> bool {
+        self.pending
+            .as_ref()
+            .is_some_and(|pending| pending.expects(message))
+    }
+
+    /// Take the request that still waits for the response.
+    pub(crate) fn take_pending(&mut self) -> Option<Pending> {
+        self.pending.take()
+    }
+
+    /// Translate the response of the state machine and send it to the client.
+    pub(crate) fn respond(&mut self, message: IpcMessage) -> Result<(), std::io::Error> {
+        let response = match (self.pending.take(), message) {
+            (Some(Pending::KvGet), IpcMessage::KvValue(value)) => SocketResponse::Value {
+                value: to_string(value),
+            },
+            (Some(Pending::Barrier(name)), IpcMessage::BroadcastAllRecv(all_data)) => {
+                match all_data
+                    .into_iter()
+                    .map(to_string)
+                    .enumerate()
+                    .find(|(_, other)| *other != name)
+                {
+                    Some((i, other)) => SocketResponse::Error {
+                        message: format!(
+                            "barrier mismatch: node {} is at `{}` instead of `{}`",
+                            i, other, name
+                        ),
+                    },
+                    None => SocketResponse::Ok,
+                }
+            }
+            (Some(Pending::BroadcastAll), IpcMessage::BroadcastAllRecv(all_data)) => {
+                SocketResponse::AllData {
+                    data: all_data.into_iter().map(to_string).collect(),
+                }
+            }
+            (Some(Pending::Receive), IpcMessage::Send(data)) => SocketResponse::Data {
+                data: to_string(data),
+            },
+            (Some(Pending::Wait), IpcMessage::Wait) => SocketResponse::Ok,
+            (_, message) => {
+                return Err(std::io::Error::other(format!(
+                    "unexpected response {:?}",
+                    message
+                )))
+            }
+        };
+        self.send(&response)
+    }
+
+    /// Send the error to the client before closing the connection.
+    pub(crate) fn send_error(&mut self, message: String) -> Result<(), std::io::Error> {
+        self.send(&SocketResponse::Error { message })
+    }
+
+    /// Write as much buffered data as possible.
+    pub(crate) fn flush(&mut self) -> Result<(), std::io::Error> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, response: &SocketResponse) -> Result<(), std::io::Error> {
+        let start = begin_frame(&mut self.output);
+        if let Err(e) = serde_json::to_writer(&mut self.output, response) {
+            self.output.truncate(start);
+            return Err(std::io::Error::other(e));
+        }
+        end_frame(&mut self.output, start).map_err(std::io::Error::other)?;
+        self.flush()
+    }
+}
+
+/// What to do with the request.
+pub(crate) enum SocketAction {
+    /// Pass the message to the state machine.
+    Forward(IpcMessage),
+    /// Write the message to the event log.
+    Log(String),
+    /// The request was handled by the client.
+    None,
+}
+
+/// The request that waits for the response.
+pub(crate) enum Pending {
+    KvGet,
+    Barrier(String),
+    BroadcastAll,
+    Receive,
+    Wait,
+}
+
+impl Pending {
+    /// Returns `true` if the message is the response to this request.
+    pub(crate) fn expects(&self, message: &IpcMessage) -> bool {
+        matches!(
+            (self, message),
+            (Self::KvGet, IpcMessage::KvValue(..))
+                | (Self::Barrier(..), IpcMessage::BroadcastAllRecv(..))
+                | (Self::BroadcastAll, IpcMessage::BroadcastAllRecv(..))
+                | (Self::Receive, IpcMessage::Send(..))
+                | (Self::Wait, IpcMessage::Wait)
+        )
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum SocketRequest {
+    Hello { version: u32 },
+    Step { name: String },
+    Barrier { name: String },
+    BroadcastSend { data: String },
+    BroadcastReceive,
+    BroadcastWait,
+    BroadcastAll { data: String },
+    KvPut { key: String, value: String },
+    KvGet { key: String },
+    Log { message: String },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketResponse {
+    Hello { version: u32 },
+    Ok,
+    Data { data: String },
+    AllData { data: Vec<String> },
+    Value { value: String },
+    Error { message: String },
+}
+
+fn to_string(data: Vec<u8>) -> String {
+    String::from_utf8(data).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// The version of the socket protocol.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
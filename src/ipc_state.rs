@@ -1,12 +1,11 @@
 use std::collections::HashMap;
 
-use mio::Poll;
-use mio::Token;
-
 use crate::format_error;
+use crate::trace_event;
 use crate::BroadcastPayload;
-use crate::IpcClient;
+use crate::EventLog;
 use crate::IpcMessage;
+use crate::LogEvent;
 
 pub(crate) struct IpcStateMachine {
     num_nodes: usize,
@@ -14,6 +13,10 @@ pub(crate) struct IpcStateMachine {
     broadcast_initiator: Option<usize>,
     broadcasts: HashMap<usize, Broadcast>,
     broadcast_alls: HashMap<usize, Vec<u8>>,
+    /// Network-wide key-value store.
+    kv: HashMap<String, Vec<u8>>,
+    /// The nodes that wait for the keys to be put.
+    kv_waiting: Vec<(usize, String)>,
 }
 
 impl IpcStateMachine {
@@ -23,17 +26,33 @@ impl IpcStateMachine {
             broadcast_initiator: None,
             broadcasts: Default::default(),
             broadcast_alls: Default::default(),
+            kv: Default::default(),
+            kv_waiting: Default::default(),
         }
     }
 
+    /// Handle the message from the node and return the responses for the nodes.
     pub(crate) fn on_message(
         &mut self,
         message: IpcMessage,
         from_node_index: usize,
-        clients: &mut [IpcClient],
-        writer_token: Token,
-        poll: &mut Poll,
-    ) -> Result<(), std::io::Error> {
+        event_log: &mut EventLog,
+    ) -> Result<Vec<(usize, IpcMessage)>, std::io::Error> {
+        let mut responses = Vec::new();
+        if let Some((operation, payload_size)) = operation(&message) {
+            trace_event!(
+                debug,
+                node = from_node_index,
+                operation,
+                payload_size,
+                "node entered collective operation"
+            );
+            event_log.write(LogEvent::CollectiveEnter {
+                node: from_node_index,
+                operation,
+                payload_size,
+            });
+        }
         match message {
             IpcMessage::Send(payload) => {
                 if let Some(i) = self.broadcast_initiator {
@@ -57,14 +76,47 @@ impl IpcStateMachine {
             IpcMessage::BroadcastAllRecv(..) => {
                 return Err(format_error!("`BroadcastAllRecv` is `IpcClient`-only"));
             }
+            IpcMessage::KvPut(key, value) => {
+                trace_event!(debug, node = from_node_index, %key, "put key");
+                let mut i = 0;
+                while i < self.kv_waiting.len() {
+                    if self.kv_waiting[i].1 == key {
+                        let (node, _) = self.kv_waiting.swap_remove(i);
+                        responses.push((node, IpcMessage::KvValue(value.clone())));
+                    } else {
+                        i += 1;
+                    }
+                }
+                self.kv.insert(key, value);
+            }
+            IpcMessage::KvGet(key) => match self.kv.get(&key) {
+                Some(value) => {
+                    responses.push((from_node_index, IpcMessage::KvValue(value.clone())));
+                }
+                None => {
+                    trace_event!(debug, node = from_node_index, %key, "waiting for key");
+                    self.kv_waiting.push((from_node_index, key));
+                }
+            },
+            IpcMessage::KvValue(..) => {
+                return Err(format_error!("`KvValue` is `IpcClient`-only"));
+            }
+            IpcMessage::Return(..)
+            | IpcMessage::Step(..)
+            | IpcMessage::Failed(..)
+            | IpcMessage::Stats(..) => {
+                return Err(format_error!(
+                    "non-collective messages are handled by `IpcServer`"
+                ));
+            }
         }
         if self.broadcasts.len() == self.num_nodes {
-            self.finalize_broadcast(clients, writer_token, poll)?;
+            self.finalize_broadcast(&mut responses, event_log)?;
         }
         if self.broadcast_alls.len() == self.num_nodes {
-            self.finalize_broadcast_all(clients, writer_token, poll)?;
+            self.finalize_broadcast_all(&mut responses, event_log)?;
         }
-        Ok(())
+        Ok(responses)
     }
 
     fn insert_broadcast(&mut self, i: usize, broadcast: Broadcast) -> Result<(), std::io::Error> {
@@ -82,9 +134,8 @@ impl IpcStateMachine {
 
     fn finalize_broadcast(
         &mut self,
-        clients: &mut [IpcClient],
-        writer_token: Token,
-        poll: &mut Poll,
+        responses: &mut Vec<(usize, IpcMessage)>,
+        event_log: &mut EventLog,
     ) -> Result<(), std::io::Error> {
         let initiator = match self.broadcast_initiator {
             Some(initiator) => initiator,
@@ -99,14 +150,29 @@ impl IpcStateMachine {
             Broadcast::Send(data) => data,
             _ => return Err(std::io::Error::other("initiator sent wrong message")),
         };
+        let mut participants: Vec<usize> = self.broadcasts.keys().copied().collect();
+        participants.sort_unstable();
+        trace_event!(
+            debug,
+            initiator,
+            payload_size = payload.len(),
+            "completed broadcast_one"
+        );
+        event_log.write(LogEvent::Collective {
+            operation: "broadcast_one",
+            payload_sizes: participants
+                .iter()
+                .map(|i| if *i == initiator { payload.len() } else { 0 })
+                .collect(),
+            participants,
+        });
         for (i, broadcast) in self.broadcasts.drain() {
             let message = match broadcast {
                 Broadcast::Receive => IpcMessage::Send(payload.clone()),
                 Broadcast::Wait => IpcMessage::Wait,
                 _ => continue,
             };
-            clients[i].send(&message)?;
-            clients[i].send_finalize(writer_token, poll)?;
+            responses.push((i, message));
         }
         self.broadcasts.clear();
         self.broadcast_initiator = None;
@@ -128,24 +194,42 @@ impl IpcStateMachine {
 
     fn finalize_broadcast_all(
         &mut self,
-        clients: &mut [IpcClient],
-        writer_token: Token,
-        poll: &mut Poll,
+        responses: &mut Vec<(usize, IpcMessage)>,
+        event_log: &mut EventLog,
     ) -> Result<(), std::io::Error> {
-        let mut payload = vec![Vec::new(); clients.len()];
+        let mut payload = vec![Vec::new(); self.num_nodes];
         for (i, value) in self.broadcast_alls.drain() {
             payload[i] = value;
         }
-        for client in clients.iter_mut() {
-            let message = IpcMessage::BroadcastAllRecv(payload.clone());
-            client.send(&message)?;
-            client.send_finalize(writer_token, poll)?;
+        trace_event!(
+            debug,
+            participants = payload.len(),
+            "completed broadcast_all"
+        );
+        event_log.write(LogEvent::Collective {
+            operation: "broadcast_all",
+            participants: (0..payload.len()).collect(),
+            payload_sizes: payload.iter().map(Vec::len).collect(),
+        });
+        for i in 0..self.num_nodes {
+            responses.push((i, IpcMessage::BroadcastAllRecv(payload.clone())));
         }
         self.broadcast_alls.clear();
         Ok(())
     }
 }
 
+/// Returns the name and the payload size of the collective operation.
+fn operation(message: &IpcMessage) -> Option<(&'static str, usize)> {
+    match message {
+        IpcMessage::Send(payload) => Some(("broadcast_send", payload.len())),
+        IpcMessage::Receive => Some(("broadcast_receive", 0)),
+        IpcMessage::Wait => Some(("broadcast_wait", 0)),
+        IpcMessage::BroadcastAllSend(payload) => Some(("broadcast_all", payload.len())),
+        _ => None,
+    }
+}
+
 #[derive(Clone)]
 enum Broadcast {
     Send(BroadcastPayload),
@@ -0,0 +1,79 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::NodeOutcome;
+
+/// Write JUnit XML report where each node is a test case.
+///
+/// The output of the failed nodes is attached to their failures.
+pub(crate) fn write_junit<T>(
+    path: &Path,
+    outcomes: &[NodeOutcome<T>],
+    elapsed: Duration,
+) -> Result<(), std::io::Error> {
+    let failures = outcomes.iter().filter(|o| !o.is_success()).count();
+    let mut xml = String::with_capacity(4096);
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        outcomes.len(),
+        failures,
+        elapsed.as_secs_f64()
+    );
+    let _ = writeln!(
+        xml,
+        "  <testsuite name=\"testnet\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        outcomes.len(),
+        failures,
+        elapsed.as_secs_f64()
+    );
+    for outcome in outcomes.iter() {
+        let report = &outcome.report;
+        let _ = write!(
+            xml,
+            "    <testcase classname=\"testnet\" name=\"{}\"",
+            escape(&report.name)
+        );
+        if outcome.is_success() {
+            xml.push_str("/>\n");
+            continue;
+        }
+        xml.push_str(">\n");
+        let mut message = format!("exited with {}, last step {}", report.status, report.step);
+        if let Some(name) = report.step_name.as_ref() {
+            let _ = write!(message, " {:?}", name);
+        }
+        let _ = writeln!(
+            xml,
+            "      <failure message=\"{}\">{}</failure>",
+            escape(&message),
+            escape(&report.output.join("\n"))
+        );
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n");
+    xml.push_str("</testsuites>\n");
+    File::create(path)?.write_all(xml.as_bytes())
+}
+
+/// Escape special characters and remove the ones that are not allowed in XML.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(ch),
+            ch if ch.is_control() => {}
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
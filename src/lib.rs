@@ -1,24 +1,65 @@
 #![doc = include_str!("../README.md")]
+#[cfg(feature = "tokio")]
+mod async_context;
+mod builder;
+mod command;
 mod config;
 mod context;
+mod duration;
 mod error;
+mod event_log;
 mod ipc_client;
 mod ipc_message;
 mod ipc_server;
+mod ipc_socket;
 mod ipc_state;
+mod junit;
 mod netlink;
 mod network;
+mod node_mains;
+mod outcome;
+mod output;
 mod pipe;
 mod process;
+mod runtime;
+mod stats;
+mod support;
+#[cfg(feature = "macros")]
+mod test_attr;
+mod trace;
 
+#[cfg(feature = "tokio")]
+pub use self::async_context::*;
+pub use self::builder::*;
+pub use self::command::*;
 pub use self::config::*;
 pub use self::context::*;
-pub(crate) use self::error::*;
+pub use self::duration::*;
+pub use self::error::*;
+pub(crate) use self::event_log::*;
 pub(crate) use self::ipc_client::*;
 pub(crate) use self::ipc_message::*;
 pub(crate) use self::ipc_server::*;
+pub(crate) use self::ipc_socket::*;
 pub(crate) use self::ipc_state::*;
+pub(crate) use self::junit::*;
 pub(crate) use self::netlink::*;
 pub use self::network::*;
+pub use self::node_mains::*;
+pub use self::outcome::*;
+pub(crate) use self::output::*;
 pub(crate) use self::pipe::*;
 pub(crate) use self::process::*;
+pub use self::runtime::*;
+pub use self::stats::*;
+pub use self::support::*;
+pub(crate) use self::trace::*;
+#[cfg(feature = "macros")]
+pub use testnet_macros::test;
+
+/// The items that are used by the code that `#[testnet::test]` generates.
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::test_attr::*;
+}
@@ -2,40 +2,192 @@
 Command line tool that helps you test your distributed applications using a virtual network via Linux namespaces.
 */
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::ffi::OsString;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Command;
 use std::process::ExitCode;
+use std::time::Duration;
 
 use bincode::decode_from_slice;
 use bincode::encode_to_vec;
 use bincode::Decode;
 use bincode::Encode;
 use clap::Parser;
+use clap::Subcommand;
+use ipnet::IpNet;
+use nix::sys::signal::SigSet;
+use nix::sys::signal::Signal;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde::Deserializer;
+use testnet::parse_duration;
+use testnet::CallbackResult;
 use testnet::Context;
+use testnet::Impairments;
 use testnet::NetConfig;
+use testnet::NetOptions;
 use testnet::Network;
 use testnet::NodeConfig;
+use testnet::NodeFailure;
+use testnet::NodeOutcome;
+use testnet::OutputConfig;
+use testnet::OutputRoute;
+use testnet::RunningNetwork;
 
 #[derive(Parser)]
 #[command(
     about = "Testnet — run your distributed application in a test network.",
     long_about = None,
     trailing_var_arg = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Subcommands>,
     /// Print version.
     #[clap(long, action)]
     version: bool,
-    #[clap(short = 'n', long, default_value = "2")]
-    nodes: usize,
+    #[command(flatten)]
+    network: NetworkArgs,
     /// Command to run.
-    program: OsString,
+    #[clap(required_unless_present_any = ["topology", "node"])]
+    program: Option<OsString>,
     /// Command arguments.
     #[clap(allow_hyphen_values = true)]
     args: Vec<OsString>,
 }
 
+/// The options that describe the network.
+#[derive(clap::Args)]
+struct NetworkArgs {
+    #[clap(short = 'n', long, default_value = "2", conflicts_with_all = ["topology", "node"])]
+    nodes: usize,
+    /// Read the nodes from the TOML file.
+    #[clap(short = 'f', long, value_name = "FILE", conflicts_with = "node")]
+    topology: Option<PathBuf>,
+    /// Add the node with the specified name (e.g. `seed=./server --seed`).
+    ///
+    /// `NAME*COUNT` adds COUNT nodes named NAME1, NAME2 etc.
+    /// The command is split on whitespace; the nodes without the command run PROGRAM.
+    #[clap(long, value_name = "NAME[*COUNT][=COMMAND]", value_parser = NodeArg::parse)]
+    node: Vec<NodeArg>,
+    /// Prefix nodes' output lines with the time elapsed since the network start.
+    #[clap(short = 't', long, action)]
+    timestamps: bool,
+    /// Write the timeline of the run to the file in JSON Lines format.
+    #[clap(long, value_name = "FILE")]
+    event_log: Option<PathBuf>,
+    /// Write the spans of nodes and steps to the file in Chrome trace event format.
+    #[clap(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+    /// Write JUnit XML report to the file.
+    #[clap(long, value_name = "FILE")]
+    junit: Option<PathBuf>,
+    /// Write nodes' stdout to the file instead of printing it to stderr
+    /// (use `/dev/stdout` to pipe the output into another program).
+    #[clap(long, value_name = "FILE")]
+    stdout: Option<PathBuf>,
+    /// Write nodes' stderr to the file instead of printing it to stderr.
+    #[clap(long, value_name = "FILE")]
+    stderr: Option<PathBuf>,
+    /// Freeze the nodes when a node fails and keep them for inspection
+    /// until enter is pressed or DURATION (e.g. `300s`) expires.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pause_on_failure: Option<Duration>,
+}
+
+impl NetworkArgs {
+    /// Launch the network that runs the commands on the nodes.
+    ///
+    /// In keep-alive mode the commands are optional,
+    /// and the nodes stay idle after launching them until they are terminated.
+    fn launch(
+        self,
+        program: Option<OsString>,
+        args: Vec<OsString>,
+        keep_alive: bool,
+    ) -> Result<Network, Box<dyn std::error::Error>> {
+        let default_command = program.map(|program| NodeCommand {
+            program,
+            args,
+            env: Default::default(),
+        });
+        let (nodes, commands) = match self.topology.as_ref() {
+            Some(path) => Topology::read(path)?.into_nodes(default_command)?,
+            None if !self.node.is_empty() => NodeArg::into_nodes(&self.node, default_command),
+            None => {
+                if default_command.is_none() && !keep_alive {
+                    return Err("no command specified".into());
+                }
+                (
+                    vec![NodeConfig::default(); self.nodes],
+                    vec![default_command; self.nodes],
+                )
+            }
+        };
+        if !keep_alive {
+            if let Some(i) = commands.iter().position(Option::is_none) {
+                return Err(match nodes[i].name.as_str() {
+                    "" => format!("node {}: no command specified", i),
+                    name => format!("node `{}`: no command specified", name),
+                }
+                .into());
+            }
+        }
+        let config = NetConfig {
+            main: |mut context| {
+                let env = Environment::new(&context);
+                let all_data = context.broadcast_all(env.encode()?)?;
+                let Some(node_command) = &commands[context.current_node_index()] else {
+                    return idle();
+                };
+                let mut command = Command::new(&node_command.program);
+                for (i, data) in all_data.into_iter().enumerate() {
+                    let env = Environment::decode(&data)?;
+                    let infix = i.to_string();
+                    env.set_for_command(&infix, &mut command);
+                }
+                env.set_for_command("NODE", &mut command);
+                command.envs(node_command.env.iter());
+                command.args(&node_command.args);
+                if !keep_alive {
+                    return Err(command.exec().into());
+                }
+                // the command inherits the node's output and process group
+                command.spawn()?;
+                idle()
+            },
+            nodes,
+        };
+        let mut options = NetOptions::default().with_output(
+            OutputConfig::default()
+                .with_stdout(output_route(self.stdout))
+                .with_stderr(output_route(self.stderr))
+                .with_timestamps(self.timestamps),
+        );
+        options.event_log = self.event_log;
+        options.trace = self.trace;
+        options.junit = self.junit;
+        options.pause_on_failure = self.pause_on_failure;
+        Ok(Network::with_options(config, options)?)
+    }
+}
+
+/// Keep the node's namespaces alive until the node is terminated.
+///
+/// The node exits successfully on SIGTERM, hence `testnet down` is not reported as a failure.
+fn idle() -> CallbackResult {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+    signals.wait()?;
+    Ok(())
+}
+
 fn main() -> ExitCode {
     match do_main() {
         Ok(_) => ExitCode::SUCCESS,
@@ -52,24 +204,459 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         println!("{}", env!("VERSION"));
         return Ok(());
     }
-    let config = NetConfig {
-        main: |mut context| {
-            let env = Environment::new(&context);
-            let all_data = context.broadcast_all(env.encode()?)?;
-            let mut command = Command::new(&args.program);
-            for (i, data) in all_data.into_iter().enumerate() {
-                let env = Environment::decode(&data)?;
-                let infix = i.to_string();
-                env.set_for_command(&infix, &mut command);
+    if let Some(command) = args.command {
+        return command.run();
+    }
+    let network = args.network.launch(args.program, args.args, false)?;
+    check_outcomes(network.wait()?)
+}
+
+/// Fail with the report of every node if some nodes failed.
+fn check_outcomes(outcomes: Vec<NodeOutcome<()>>) -> Result<(), Box<dyn std::error::Error>> {
+    if outcomes.iter().all(NodeOutcome::is_success) {
+        return Ok(());
+    }
+    Err(NodeFailure {
+        nodes: outcomes.into_iter().map(|outcome| outcome.report).collect(),
+    }
+    .into())
+}
+
+fn output_route(path: Option<PathBuf>) -> OutputRoute {
+    match path {
+        Some(path) => OutputRoute::File(path),
+        None => OutputRoute::Stderr,
+    }
+}
+
+#[derive(Subcommand)]
+enum Subcommands {
+    #[command(flatten)]
+    Node(NodeSubcommand),
+    #[command(flatten)]
+    Debug(DebugSubcommand),
+    /// Launch the network and keep it running until interrupted or stopped by `testnet down`.
+    ///
+    /// The nodes launch their commands (if any) in the background and then stay idle.
+    #[command(trailing_var_arg = true)]
+    Up {
+        #[command(flatten)]
+        network: NetworkArgs,
+        /// Command to run.
+        program: Option<OsString>,
+        /// Command arguments.
+        #[clap(allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    /// Stop the network that was launched by `testnet up`.
+    Down {
+        /// Network id (the only running network by default).
+        #[clap(long, value_name = "ID")]
+        network: Option<u32>,
+    },
+}
+
+impl Subcommands {
+    fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Self::Node(command) => command.run(),
+            Self::Debug(command) => command.run(),
+            Self::Up {
+                network,
+                program,
+                args,
+            } => {
+                let network = network.launch(program, args, true)?;
+                let id = network.id();
+                // the nodes are in their own process groups and do not receive the signals
+                let mut signals = SigSet::empty();
+                signals.add(Signal::SIGINT);
+                signals.add(Signal::SIGTERM);
+                signals.thread_block()?;
+                std::thread::spawn(move || {
+                    if signals.wait().is_ok() {
+                        if let Err(e) = find_network(Some(id)).and_then(|network| {
+                            network.stop()?;
+                            Ok(())
+                        }) {
+                            eprintln!("failed to stop network {}: {}", id, e);
+                        }
+                    }
+                });
+                eprintln!(
+                    "network {} is up, press Ctrl+C or run `testnet down` to stop it",
+                    id
+                );
+                check_outcomes(network.wait()?)
             }
-            env.set_for_command("NODE", &mut command);
-            Err(command.args(&args.args).exec().into())
+            Self::Down { network } => {
+                find_network(network)?.stop()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Find the running network by id or the only running network if the id is not specified.
+fn find_network(id: Option<u32>) -> Result<RunningNetwork, Box<dyn std::error::Error>> {
+    let mut networks = RunningNetwork::list()?;
+    match (id, networks.len()) {
+        (Some(id), _) => match networks.iter().position(|network| network.id() == id) {
+            Some(i) => Ok(networks.swap_remove(i)),
+            None => Err(format!("network {} is not running", id).into()),
         },
-        nodes: vec![NodeConfig::default(); args.nodes],
-    };
-    let network = Network::new(config)?;
-    network.wait()?;
-    Ok(())
+        (None, 0) => Err("no running networks".into()),
+        (None, 1) => Ok(networks.remove(0)),
+        (None, _) => {
+            let ids: Vec<String> = networks.iter().map(|n| n.id().to_string()).collect();
+            Err(format!(
+                "multiple networks are running ({}), please specify `--network`",
+                ids.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+/// Coordination commands for the programs that run on the nodes.
+///
+/// These commands use the IPC channel of the node that launched them.
+#[derive(Subcommand)]
+enum NodeSubcommand {
+    /// Wait until all the nodes reach the barrier with the same name.
+    Barrier { name: String },
+    /// Send the value to all the nodes and print the values of all the nodes one per line.
+    BroadcastAll { value: String },
+    /// Access network-wide key-value store.
+    #[command(subcommand)]
+    Kv(KvSubcommand),
+}
+
+#[derive(Subcommand)]
+enum KvSubcommand {
+    /// Store the value under the key.
+    Put { key: String, value: String },
+    /// Print the value of the key. Waits until some node puts the key.
+    Get { key: String },
+}
+
+impl NodeSubcommand {
+    fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        // SAFETY: the descriptors are inherited from the node and are not used by this process
+        let mut context = unsafe { Context::from_env()? };
+        match self {
+            Self::Barrier { name } => {
+                let all_names = context.broadcast_all_string(name.clone())?;
+                if let Some((i, other)) = all_names
+                    .iter()
+                    .enumerate()
+                    .find(|(_, other)| **other != name)
+                {
+                    return Err(format!(
+                        "barrier mismatch: node {} is at `{}` instead of `{}`",
+                        i, other, name
+                    )
+                    .into());
+                }
+            }
+            Self::BroadcastAll { value } => {
+                for value in context.broadcast_all_string(value)?.into_iter() {
+                    println!("{}", value);
+                }
+            }
+            Self::Kv(KvSubcommand::Put { key, value }) => context.kv_put(key, value.into())?,
+            Self::Kv(KvSubcommand::Get { key }) => {
+                let value = context.kv_get(key)?;
+                println!("{}", String::from_utf8_lossy(&value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commands that enter the namespaces of the running network's node.
+///
+/// The nodes are looked up in the runtime directory (see `RunningNetwork`).
+#[derive(Subcommand)]
+enum DebugSubcommand {
+    /// Run the command in the node's namespaces.
+    Exec {
+        /// Network id (the only running network by default).
+        #[clap(long, value_name = "ID")]
+        network: Option<u32>,
+        /// Node name or index.
+        node: String,
+        /// Command to run.
+        #[clap(last = true, required = true)]
+        command: Vec<OsString>,
+    },
+    /// Run interactive shell (`$SHELL` or `/bin/sh`) in the node's namespaces.
+    Shell {
+        /// Network id (the only running network by default).
+        #[clap(long, value_name = "ID")]
+        network: Option<u32>,
+        /// Node name or index.
+        node: String,
+    },
+}
+
+impl DebugSubcommand {
+    fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        let (network_id, node, mut command) = match self {
+            Self::Exec {
+                network,
+                node,
+                command,
+            } => {
+                let mut c = Command::new(&command[0]);
+                c.args(&command[1..]);
+                (network, node, c)
+            }
+            Self::Shell { network, node } => {
+                let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+                (network, node, Command::new(shell))
+            }
+        };
+        let network = find_network(network_id)?;
+        let node = network
+            .node(&node)
+            .ok_or_else(|| format!("node `{}` not found in network {}", node, network.id()))?;
+        node.enter()?;
+        Err(command.exec().into())
+    }
+}
+
+/// The nodes and their commands.
+type Nodes = (Vec<NodeConfig>, Vec<Option<NodeCommand>>);
+
+/// The command that the node runs.
+#[derive(Clone)]
+struct NodeCommand {
+    program: OsString,
+    args: Vec<OsString>,
+    env: BTreeMap<String, String>,
+}
+
+/// Node specification from the command line.
+#[derive(Clone)]
+struct NodeArg {
+    name: String,
+    count: Option<usize>,
+    command: Option<Vec<String>>,
+}
+
+impl NodeArg {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (name, command) = match s.split_once('=') {
+            Some((name, command)) => {
+                let command: Vec<String> = command.split_whitespace().map(Into::into).collect();
+                if command.is_empty() {
+                    return Err("empty command".into());
+                }
+                (name, Some(command))
+            }
+            None => (s, None),
+        };
+        let (name, count) = match name.split_once('*') {
+            Some((name, count)) => {
+                let count: usize = count
+                    .parse()
+                    .map_err(|_| format!("invalid node count `{}`", count))?;
+                (name, Some(count))
+            }
+            None => (name, None),
+        };
+        if name.is_empty() {
+            return Err("empty node name".into());
+        }
+        Ok(Self {
+            name: name.into(),
+            count,
+            command,
+        })
+    }
+
+    fn into_nodes(node_args: &[NodeArg], default_command: Option<NodeCommand>) -> Nodes {
+        let mut nodes = Vec::new();
+        let mut commands = Vec::new();
+        for node_arg in node_args.iter() {
+            let command = match node_arg.command.as_ref() {
+                Some(command) => Some(NodeCommand {
+                    program: command[0].clone().into(),
+                    args: command[1..].iter().map(Into::into).collect(),
+                    env: Default::default(),
+                }),
+                None => default_command.clone(),
+            };
+            let names = match node_arg.count {
+                Some(count) => (1..=count)
+                    .map(|i| format!("{}{}", node_arg.name, i))
+                    .collect(),
+                None => vec![node_arg.name.clone()],
+            };
+            for name in names.into_iter() {
+                nodes.push(NodeConfig::from(name));
+                commands.push(command.clone());
+            }
+        }
+        (nodes, commands)
+    }
+}
+
+/// Network topology file.
+///
+/// Node's `command`, `args` and `env` override the top-level ones.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Topology {
+    /// Default command of the nodes.
+    command: Option<String>,
+    /// Default arguments of the command.
+    #[serde(default)]
+    args: Vec<String>,
+    /// Environment variables of all the nodes.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Subnets from which the node addresses are assigned.
+    ///
+    /// These are not separate networks: all the nodes are connected to the same bridge.
+    #[serde(default)]
+    address_pools: Vec<AddressPoolSpec>,
+    /// Isolated networks are not supported, hence this field is rejected.
+    networks: Option<IgnoredAny>,
+    nodes: Vec<NodeSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddressPoolSpec {
+    name: String,
+    subnet: IpNet,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeSpec {
+    #[serde(default)]
+    name: String,
+    /// Interface address with the prefix length.
+    address: Option<IpNet>,
+    /// The name of the address pool from which the address is assigned.
+    address_pool: Option<String>,
+    /// Isolated networks are not supported, hence this field is rejected.
+    network: Option<IgnoredAny>,
+    command: Option<String>,
+    args: Option<Vec<String>>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    impairments: ImpairmentsSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ImpairmentsSpec {
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    delay: Duration,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    jitter: Duration,
+    #[serde(default)]
+    loss: f64,
+}
+
+impl Topology {
+    fn read(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let topology =
+            toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(topology)
+    }
+
+    fn into_nodes(
+        self,
+        default_command: Option<NodeCommand>,
+    ) -> Result<Nodes, Box<dyn std::error::Error>> {
+        if self.networks.is_some() || self.nodes.iter().any(|node| node.network.is_some()) {
+            return Err(ISOLATION_UNSUPPORTED.into());
+        }
+        let mut pools = BTreeMap::new();
+        for pool in self.address_pools.iter() {
+            let hosts = (pool.subnet.prefix_len(), pool.subnet.hosts());
+            if pools.insert(pool.name.as_str(), hosts).is_some() {
+                return Err(format!("duplicate address pool `{}`", pool.name).into());
+            }
+        }
+        // the pools skip the addresses that are assigned explicitly
+        let mut used = BTreeSet::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(address) = node.address {
+                if !used.insert(address.addr()) {
+                    return Err(format!("node {}: address {} is already in use", i, address).into());
+                }
+            }
+        }
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut commands = Vec::with_capacity(self.nodes.len());
+        for (i, node) in self.nodes.into_iter().enumerate() {
+            let ifaddr = match (node.address, node.address_pool.as_deref()) {
+                (Some(address), _) => address,
+                (None, Some(name)) => {
+                    let (prefix_len, hosts) = pools
+                        .get_mut(name)
+                        .ok_or_else(|| format!("node {}: unknown address pool `{}`", i, name))?;
+                    let addr = hosts
+                        .find(|addr| !used.contains(addr))
+                        .ok_or_else(|| format!("address pool `{}`: no addresses left", name))?;
+                    // the pools may overlap
+                    used.insert(addr);
+                    IpNet::new(addr, *prefix_len)?
+                }
+                (None, None) => Default::default(),
+            };
+            let mut command = match (node.command, self.command.as_ref()) {
+                (Some(program), _) => Some(NodeCommand {
+                    program: program.into(),
+                    args: Vec::new(),
+                    env: Default::default(),
+                }),
+                (None, Some(program)) => Some(NodeCommand {
+                    program: program.into(),
+                    args: self.args.iter().map(Into::into).collect(),
+                    env: Default::default(),
+                }),
+                (None, None) => default_command.clone(),
+            };
+            if let Some(command) = command.as_mut() {
+                if let Some(args) = node.args {
+                    command.args = args.into_iter().map(Into::into).collect();
+                }
+                command.env = self.env.clone();
+                command.env.extend(node.env);
+            }
+            nodes.push(
+                NodeConfig::new(node.name)
+                    .with_ifaddr(ifaddr)
+                    .with_impairments(Impairments {
+                        delay: node.impairments.delay,
+                        jitter: node.impairments.jitter,
+                        loss: node.impairments.loss,
+                    }),
+            );
+            commands.push(command);
+        }
+        Ok((nodes, commands))
+    }
+}
+
+const ISOLATION_UNSUPPORTED: &str = "`networks` and `network` are not supported: \
+    all the nodes are connected to the same bridge, \
+    use `address_pools` and `address_pool` to assign the addresses from the subnets";
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    use serde::de::Error;
+    let string = String::deserialize(deserializer)?;
+    parse_duration(&string).map_err(D::Error::custom)
 }
 
 #[derive(Encode, Decode)]
@@ -2,6 +2,7 @@ use std::fmt::Debug;
 use std::os::fd::AsRawFd;
 use std::os::fd::OwnedFd;
 use std::os::fd::RawFd;
+use std::time::Duration;
 
 use ipnet::IpNet;
 use netlink_packet_core::NetlinkDeserializable;
@@ -21,19 +22,30 @@ use netlink_packet_route::link::LinkAttribute;
 use netlink_packet_route::link::LinkFlags;
 use netlink_packet_route::link::LinkInfo;
 use netlink_packet_route::link::LinkMessage;
+use netlink_packet_route::tc::TcAttribute;
+use netlink_packet_route::tc::TcHandle;
+use netlink_packet_route::tc::TcMessage;
 use netlink_packet_route::RouteNetlinkMessage;
+use netlink_packet_utils::nla::DefaultNla;
+use nix::errno::Errno;
 use nix::sys::socket::socket;
 use nix::sys::socket::AddressFamily;
 use nix::sys::socket::SockFlag;
 use nix::sys::socket::SockProtocol;
 use nix::sys::socket::SockType;
 
+use crate::format_error;
+use crate::trace_event;
+use crate::Error;
+use crate::Impairments;
+use crate::InterfaceStats;
+
 pub(crate) struct Netlink {
     socket: OwnedFd,
 }
 
 impl Netlink {
-    pub(crate) fn new(protocol: SockProtocol) -> Result<Self, std::io::Error> {
+    pub(crate) fn new(protocol: SockProtocol) -> Result<Self, Error> {
         let socket = socket(
             AddressFamily::Netlink,
             SockType::Raw,
@@ -47,7 +59,7 @@ impl Netlink {
         &mut self,
         name: impl ToString,
         peer_name: impl ToString,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), Error> {
         let mut peer = LinkMessage::default();
         peer.attributes
             .push(LinkAttribute::IfName(name.to_string()));
@@ -69,7 +81,7 @@ impl Netlink {
         Ok(())
     }
 
-    pub(crate) fn new_bridge(&mut self, name: impl ToString) -> Result<(), std::io::Error> {
+    pub(crate) fn new_bridge(&mut self, name: impl ToString) -> Result<(), Error> {
         let mut link = LinkMessage::default();
         link.attributes
             .push(LinkAttribute::IfName(name.to_string()));
@@ -87,7 +99,7 @@ impl Netlink {
         Ok(())
     }
 
-    pub(crate) fn set_up(&mut self, name: impl ToString) -> Result<(), std::io::Error> {
+    pub(crate) fn set_up(&mut self, name: impl ToString) -> Result<(), Error> {
         let mut link = LinkMessage::default();
         link.attributes
             .push(LinkAttribute::IfName(name.to_string()));
@@ -101,11 +113,7 @@ impl Netlink {
         Ok(())
     }
 
-    pub(crate) fn set_bridge(
-        &mut self,
-        name: String,
-        bridge_index: u32,
-    ) -> Result<(), std::io::Error> {
+    pub(crate) fn set_bridge(&mut self, name: String, bridge_index: u32) -> Result<(), Error> {
         let mut link = LinkMessage::default();
         link.attributes.push(LinkAttribute::IfName(name));
         link.attributes
@@ -122,7 +130,7 @@ impl Netlink {
         &mut self,
         name: impl ToString,
         fd: RawFd,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(), Error> {
         let mut link = LinkMessage::default();
         link.attributes
             .push(LinkAttribute::IfName(name.to_string()));
@@ -135,7 +143,7 @@ impl Netlink {
         Ok(())
     }
 
-    pub(crate) fn set_ifaddr(&mut self, index: u32, ifaddr: IpNet) -> Result<(), std::io::Error> {
+    pub(crate) fn set_ifaddr(&mut self, index: u32, ifaddr: IpNet) -> Result<(), Error> {
         use netlink_packet_route::AddressFamily;
         let mut message = AddressMessage::default();
         message.header.prefix_len = ifaddr.prefix_len();
@@ -158,39 +166,81 @@ impl Netlink {
         Ok(())
     }
 
-    pub(crate) fn index(&mut self, name: impl ToString) -> Result<u32, std::io::Error> {
+    /// Replace the root queueing discipline of the interface with `netem`.
+    pub(crate) fn set_impairments(
+        &mut self,
+        index: u32,
+        impairments: &Impairments,
+    ) -> Result<(), Error> {
+        let mut tc = TcMessage::with_index(index as i32);
+        tc.header.parent = TcHandle::ROOT;
+        tc.attributes.push(TcAttribute::Kind("netem".into()));
+        // `struct tc_netem_qopt` is not a list of attributes, hence the raw bytes
+        let options = [
+            to_ticks(impairments.delay),
+            NETEM_LIMIT,
+            to_probability(impairments.loss),
+            0,
+            0,
+            to_ticks(impairments.jitter),
+        ];
+        tc.attributes.push(TcAttribute::Other(DefaultNla::new(
+            TCA_OPTIONS,
+            options.iter().flat_map(|x| x.to_ne_bytes()).collect(),
+        )));
+        let mut message = NetlinkMessage::from(RouteNetlinkMessage::NewQueueDiscipline(tc));
+        message.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_EXCL | NLM_F_CREATE;
+        message.finalize();
+        let message = self.send(&message)?;
+        check_ok(message)?;
+        Ok(())
+    }
+
+    pub(crate) fn index(&mut self, name: impl ToString) -> Result<u32, Error> {
+        Ok(self.link(name)?.header.index)
+    }
+
+    pub(crate) fn stats(&mut self, name: impl ToString) -> Result<InterfaceStats, Error> {
+        let link = self.link(name)?;
+        link.attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                LinkAttribute::Stats64(stats) => Some(stats.into()),
+                _ => None,
+            })
+            .ok_or_else(|| format_error!("netlink did not return interface statistics").into())
+    }
+
+    fn link(&mut self, name: impl ToString) -> Result<LinkMessage, Error> {
         let mut link = LinkMessage::default();
         link.attributes
             .push(LinkAttribute::IfName(name.to_string()));
         let mut message = NetlinkMessage::from(RouteNetlinkMessage::GetLink(link));
-        message.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+        // no ack, otherwise it would be read as the response to the next request
+        message.header.flags = NLM_F_REQUEST;
         message.finalize();
         let message = self.send(&message)?;
-        let index = match message.payload {
-            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(ref inner)) => {
-                Some(inner.header.index)
+        match message.payload {
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(inner)) => Ok(inner),
+            NetlinkPayload::Error(ref error) if error.code.is_some() => {
+                check_ok(message)?;
+                Err(format_error!("netlink returned unexpected data").into())
             }
-            _ => None,
-        };
-        match index {
-            Some(index) => Ok(index),
-            None => Err(std::io::Error::other(format!(
-                "netlink returned unexpected data: {:?}",
-                message,
-            ))),
+            other => Err(format_error!("netlink returned unexpected data: {:?}", other).into()),
         }
     }
 
     fn send<I: NetlinkSerializable + NetlinkDeserializable + Debug>(
         &mut self,
         message: &NetlinkMessage<I>,
-    ) -> Result<NetlinkMessage<I>, std::io::Error> {
+    ) -> Result<NetlinkMessage<I>, Error> {
+        trace_event!(trace, request = ?message.payload, "netlink request");
         let mut buf = vec![0_u8; message.header.length as usize];
         // Serialize the packet
         message.serialize(&mut buf[..]);
         let n = nix::unistd::write(&mut self.socket, &buf)?;
         if n != buf.len() {
-            return Err(std::io::Error::other("partial write"));
+            return Err(std::io::Error::other("partial write").into());
         }
         buf.clear();
         buf.resize(4096, 0_u8);
@@ -198,26 +248,38 @@ impl Netlink {
         buf.truncate(n);
         let message = NetlinkMessage::<I>::deserialize(&buf)
             .map_err(|e| std::io::Error::other(e.to_string()))?;
+        trace_event!(trace, response = ?message.payload, "netlink response");
         Ok(message)
     }
 }
 
-fn check_ok<I: Debug>(message: NetlinkMessage<I>) -> Result<NetlinkMessage<I>, std::io::Error> {
+fn check_ok<I: Debug>(message: NetlinkMessage<I>) -> Result<NetlinkMessage<I>, Error> {
     match message.payload {
         NetlinkPayload::Error(ref error) => {
             if let Some(code) = error.code {
-                return Err(std::io::Error::other(format!(
-                    "netlink failed with error code {}",
-                    code
-                )));
+                // error codes are negative
+                let errno = Errno::from_raw(-code.get());
+                trace_event!(debug, %errno, "netlink request failed");
+                return Err(Error::Netlink(errno));
             }
         }
         other => {
-            return Err(std::io::Error::other(format!(
-                "netlink returned unexpected data: {:?}",
-                other,
-            )));
+            return Err(format_error!("netlink returned unexpected data: {:?}", other).into());
         }
     }
     Ok(message)
 }
+
+/// Convert the duration to packet scheduler ticks (64 ns each).
+fn to_ticks(duration: Duration) -> u32 {
+    (duration.as_nanos() >> 6).try_into().unwrap_or(u32::MAX)
+}
+
+/// Scale the probability to the whole range of `u32`.
+fn to_probability(probability: f64) -> u32 {
+    (probability.clamp(0.0, 1.0) * u32::MAX as f64) as u32
+}
+
+const TCA_OPTIONS: u16 = 2;
+/// The default queue length in packets used by `tc`.
+const NETEM_LIMIT: u32 = 1000;
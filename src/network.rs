@@ -1,87 +1,201 @@
+use std::collections::HashSet;
 use std::ffi::c_int;
 use std::ffi::CString;
 use std::fs::File;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+use std::marker::PhantomData;
 use std::net::Ipv4Addr;
+use std::os::fd::AsFd;
 use std::os::fd::AsRawFd;
 use std::os::fd::FromRawFd;
 use std::os::fd::OwnedFd;
 use std::os::fd::RawFd;
+use std::os::unix::net::UnixListener;
 use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
 
+use bincode::Decode;
+use bincode::Encode;
 use ipnet::IpNet;
-use mio_pidfd::PidFd;
+use nix::fcntl::fcntl;
+use nix::fcntl::FcntlArg;
+use nix::fcntl::OFlag;
 use nix::mount::mount;
 use nix::mount::MsFlags;
+use nix::poll::poll;
+use nix::poll::PollFd;
+use nix::poll::PollFlags;
+use nix::poll::PollTimeout;
 use nix::sched::setns;
 use nix::sched::CloneFlags;
 use nix::sys::prctl::set_name;
+use nix::sys::signal::killpg;
+use nix::sys::signal::Signal;
 use nix::sys::socket::SockProtocol;
-use nix::sys::wait::WaitStatus;
 use nix::unistd::dup2;
+use nix::unistd::getuid;
 use nix::unistd::pipe;
+use nix::unistd::pipe2;
 use nix::unistd::sethostname;
-use nix::unistd::Gid;
 use nix::unistd::Pid;
-use nix::unistd::Uid;
 use tempfile::TempDir;
 
+use crate::decode_value;
+use crate::encode_value;
+use crate::format_error;
 use crate::log_format;
+use crate::namespace_flags;
 use crate::pipe_channel;
+use crate::print_lines;
+use crate::probe;
+use crate::runtime_dir;
+use crate::trace_event;
+use crate::trace_span;
+use crate::write_id_maps;
+use crate::write_junit;
 use crate::CallbackResult;
 use crate::Context;
+use crate::Error;
+use crate::EventLog;
 use crate::IpcClient;
+use crate::IpcEncodeDecode;
+use crate::IpcMessage;
 use crate::IpcServer;
+use crate::LogEvent;
 use crate::NetConfig;
+use crate::NetOptions;
 use crate::Netlink;
+use crate::NetworkBuilder;
 use crate::NodeConfig;
+use crate::NodeFailure;
+use crate::NodeFds;
+use crate::NodeMain;
+use crate::NodeOutcome;
+use crate::NodeReport;
+use crate::NodeStatus;
 use crate::PipeReceiver;
+use crate::Prefix;
+use crate::PrintMode;
 use crate::Process;
+use crate::RemoteError;
+use crate::RuntimeDir;
+use crate::ServerStop;
+use crate::VethStats;
+use crate::CONTEXT_ENV;
+use crate::SOCKET_ENV;
 
 /// Virtual network.
 ///
 /// This struct offers more granular control over the network compared to `testnet` function.
 /// See `testnet` for more details.
-pub struct Network {
+pub struct Network<T = ()> {
     main: Process,
+    outcomes: File,
+    phantom: PhantomData<T>,
 }
 
 impl Network {
+    /// Create the builder for the network.
+    pub fn builder() -> NetworkBuilder {
+        NetworkBuilder::new()
+    }
+}
+
+impl<T: Encode + Decode> Network<T> {
     /// Create new virtual network with the specified configuration.
     ///
     /// Launches child processes in their own network namespaces.
     /// See `testnet` for more details.
-    pub fn new<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackResult + Clone>(
+    pub fn new<C: Into<NodeConfig>, F: NodeMain<Value = T> + Clone>(
+        config: NetConfig<C, F>,
+    ) -> Result<Self, Error> {
+        Self::with_options(config, Default::default())
+    }
+
+    /// Create new virtual network with the specified configuration and network-wide options.
+    pub fn with_options<C: Into<NodeConfig>, F: NodeMain<Value = T> + Clone>(
         config: NetConfig<C, F>,
-    ) -> Result<Self, std::io::Error> {
+        options: NetOptions,
+    ) -> Result<Self, Error> {
+        let privileged = options.is_privileged();
+        if options.skip_unsupported {
+            probe(privileged).map_err(|e| match e {
+                Error::Unsupported(reason) => Error::Skipped(reason),
+                other => other,
+            })?;
+        }
         let (sender, receiver) = pipe_channel()?;
+        let (outcomes_in, outcomes_out) = pipe2(OFlag::O_CLOEXEC)?;
+        let outcomes_in_fd = outcomes_in.as_raw_fd();
+        // the switch process runs as root in its user namespace
+        let runtime_dir = runtime_dir(getuid());
         let main = Process::spawn(
-            || network_switch_main(receiver.into(), config),
+            || {
+                // drop unused pipe end
+                unsafe { OwnedFd::from_raw_fd(outcomes_in_fd) };
+                network_switch_main(receiver.into(), outcomes_out, config, options, runtime_dir)
+            },
             STACK_SIZE,
-            CloneFlags::CLONE_NEWNET
-                | CloneFlags::CLONE_NEWUSER
-                | CloneFlags::CLONE_NEWUTS
-                | CloneFlags::CLONE_NEWNS,
-        )?;
-        // update uid map
-        std::fs::write(
-            format!("/proc/{}/uid_map", main.id()),
-            format!("0 {} 1", Uid::current()),
-        )?;
-        // setgroups deny
-        std::fs::write(format!("/proc/{}/setgroups", main.id()), "deny")?;
-        // update gid map
-        std::fs::write(
-            format!("/proc/{}/gid_map", main.id()),
-            format!("0 {} 1", Gid::current()),
-        )?;
+            namespace_flags(privileged),
+        )
+        .map_err(|e| Error::Namespace(e.into()))?;
+        if !privileged {
+            write_id_maps(main.id()).map_err(Error::Namespace)?;
+        }
         // notify the child process
         sender.close()?;
-        Ok(Self { main })
+        Ok(Self {
+            main,
+            outcomes: outcomes_in.into(),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Network id.
+    ///
+    /// This is the process id of the switch process; the same id is used by [`RunningNetwork`](crate::RunningNetwork).
+    pub fn id(&self) -> u32 {
+        self.main.id().as_raw() as u32
     }
 
     /// Wait until the child processes exit successfully or one of the node processes fails.
-    pub fn wait(&self) -> Result<WaitStatus, std::io::Error> {
-        Ok(self.main.wait()?)
+    ///
+    /// Returns the outcome of each node's `main` function.
+    pub fn wait(&self) -> Result<Vec<NodeOutcome<T>>, Error> {
+        // read the outcomes before waiting to not block the writer
+        let mut buf = Vec::new();
+        (&self.outcomes).read_to_end(&mut buf)?;
+        let status = self.main.wait()?;
+        let outcomes = match <SwitchResult as IpcEncodeDecode>::decode(&buf) {
+            Ok(Some((Ok(outcomes), _))) => outcomes,
+            Ok(Some((Err(e), _))) => return Err(e.into()),
+            Ok(None) => {
+                return Err(Error::Io(format_error!(
+                    "network main exited with {}",
+                    NodeStatus::from(status)
+                )))
+            }
+            Err(e) => return Err(Error::Ipc(e.to_string())),
+        };
+        outcomes
+            .into_iter()
+            .map(|outcome| {
+                let value = match outcome.value {
+                    Some(value) => {
+                        Some(decode_value::<T>(&value).map_err(|e| Error::Ipc(e.to_string()))?)
+                    }
+                    None => None,
+                };
+                Ok(NodeOutcome {
+                    report: outcome.report,
+                    value,
+                })
+            })
+            .collect()
     }
 }
 
@@ -89,64 +203,117 @@ impl Network {
 ///
 /// Launches virtual network using Linux network namespaces
 /// and runs specified `main` function in each node's process.
-/// If a node process exits with non-zero value, the test fails.
-/// If all node processes exit with zero values, the test succeeds.
+/// If a node process exits with non-zero value, the test fails
+/// and [`NodeFailure`] that describes every node is returned as the error.
+/// If all node processes exit with zero values, the test succeeds
+/// and the values returned by each node's `main` function are returned.
 ///
 /// This function internally launches child process in its own network namespace,
 /// and this process in turn launches another child process for each network node
 /// (again in its own network namespace).
 /// Nodes do not have access to the outside network.
-pub fn testnet<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackResult + Clone>(
+pub fn testnet<C: Into<NodeConfig>, F: NodeMain + Clone>(
     config: NetConfig<C, F>,
-) -> Result<(), std::io::Error> {
-    let network = Network::new(config)?;
-    match network.wait()? {
-        WaitStatus::Exited(_, 0) => Ok(()),
-        _ => Err(std::io::Error::other("some nodes failed")),
+) -> Result<Vec<NodeOutcome<F::Value>>, Error>
+where
+    F::Value: Encode + Decode,
+{
+    testnet_with_options(config, Default::default())
+}
+
+/// Same as [`testnet`] but with the specified network-wide options.
+pub fn testnet_with_options<C: Into<NodeConfig>, F: NodeMain + Clone>(
+    config: NetConfig<C, F>,
+    options: NetOptions,
+) -> Result<Vec<NodeOutcome<F::Value>>, Error>
+where
+    F::Value: Encode + Decode,
+{
+    let network = Network::with_options(config, options)?;
+    let outcomes = network.wait()?;
+    if outcomes.iter().all(NodeOutcome::is_success) {
+        Ok(outcomes)
+    } else {
+        Err(Error::NodeFailure(NodeFailure {
+            nodes: outcomes.into_iter().map(|outcome| outcome.report).collect(),
+        }))
     }
 }
 
-fn network_switch_main<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackResult + Clone>(
+fn network_switch_main<C: Into<NodeConfig>, F: NodeMain + Clone>(
     receiver: PipeReceiver,
+    outcomes: OwnedFd,
     config: NetConfig<C, F>,
-) -> c_int {
-    match do_network_switch_main(receiver, config) {
+    options: NetOptions,
+    runtime_dir: PathBuf,
+) -> c_int
+where
+    F::Value: Encode,
+{
+    let result =
+        do_network_switch_main(receiver, outcomes.as_raw_fd(), config, options, runtime_dir);
+    let ret = match result {
         Ok(_) => 0,
-        Err(e) => {
+        Err(ref e) => {
             log_format!("network main failed: {}", e);
             1
         }
+    };
+    // the parent process decides whether the nodes failed
+    let result: SwitchResult = result.map_err(Into::into);
+    let mut buf = Vec::new();
+    if let Err(e) = IpcEncodeDecode::encode(&result, &mut buf) {
+        log_format!("failed to encode the outcomes: {}", e);
+        return 1;
+    }
+    if let Err(e) = File::from(outcomes).write_all(&buf) {
+        log_format!("failed to send the outcomes: {}", e);
+        return 1;
     }
+    ret
 }
 
-fn do_network_switch_main<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackResult + Clone>(
+fn do_network_switch_main<C: Into<NodeConfig>, F: NodeMain + Clone>(
     receiver: PipeReceiver,
+    outcomes_fd: RawFd,
     config: NetConfig<C, F>,
-) -> CallbackResult {
+    options: NetOptions,
+    runtime_dir: PathBuf,
+) -> Result<Vec<NodeOutcome<Vec<u8>>>, Error>
+where
+    F::Value: Encode,
+{
+    let start = Instant::now();
+    let _span = trace_span!(INFO, "testnet", nodes = config.nodes.len());
     set_process_name(SWITCH_NAME)?;
-    sethostname(SWITCH_NAME)?;
     // wait for uid/gid mappings to be done by the parent process
     receiver.wait_until_closed()?;
+    sethostname(SWITCH_NAME).map_err(|e| Error::Namespace(e.into()))?;
     let mut netlink = Netlink::new(SockProtocol::NetlinkRoute)?;
     netlink.new_bridge(BRIDGE_IFNAME)?;
+    trace_event!(debug, name = BRIDGE_IFNAME, "created bridge");
     let mut nodes: Vec<Process> = Vec::with_capacity(config.nodes.len());
-    let net = IpNet::new(Ipv4Addr::new(10, 84, 0, 0).into(), 16)?;
+    let net = IpNet::new(Ipv4Addr::new(10, 84, 0, 0).into(), 16).map_err(std::io::Error::other)?;
     let mut all_node_configs = Vec::with_capacity(config.nodes.len());
     for (i, node_config) in config.nodes.into_iter().enumerate() {
         let mut node_config: NodeConfig = node_config.into();
         if node_config.name.is_empty() {
             node_config.name = outer_ifname(i);
         }
-        if node_config.ifaddr.addr().is_unspecified() {
-            node_config.ifaddr = IpNet::new(
-                net.hosts()
-                    .nth(i)
-                    .ok_or("exhausted available IP adddress range")?,
-                net.prefix_len(),
-            )?;
+        // the name is used as the file name of the node's log
+        if matches!(node_config.name.as_str(), "." | "..")
+            || node_config.name.contains(['/', '\0', '\n'])
+        {
+            return Err(
+                format_error!("invalid node name `{}`", node_config.name.escape_debug()).into(),
+            );
         }
         all_node_configs.push(node_config);
     }
+    assign_addresses(&mut all_node_configs, net)?;
+    if let Some(dir) = options.output.dir.as_ref() {
+        std::fs::create_dir_all(dir)?;
+    }
     let workdir = TempDir::new()?;
     let hosts = workdir.path().join("hosts");
     std::fs::write(
@@ -159,31 +326,46 @@ fn do_network_switch_main<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackRes
                 buf
             }),
     )?;
+    // do not propagate the mounts to the parent namespace in privileged mode
     if let Err(e) = mount(
-        Some(hosts.as_path()),
-        "/etc/hosts",
         None::<&Path>,
-        MsFlags::MS_BIND,
+        "/",
         None::<&Path>,
-    ) {
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&Path>,
+    )
+    .and_then(|_| {
+        mount(
+            Some(hosts.as_path()),
+            "/etc/hosts",
+            None::<&Path>,
+            MsFlags::MS_BIND,
+            None::<&Path>,
+        )
+    }) {
         log_format!(
-            "WARNING: bind mount failed ({}), node hostnames will not be available",
+            warn: "WARNING: bind mount failed ({}), node hostnames will not be available",
             e
         );
     }
     // TODO fall back on nss modules??? still will not work for musl
-    let mut ipc_fds: Vec<(OwnedFd, OwnedFd, PidFd, OwnedFd, String)> =
-        Vec::with_capacity(all_node_configs.len());
+    let mut ipc_fds: Vec<NodeFds> = Vec::with_capacity(all_node_configs.len());
     for i in 0..all_node_configs.len() {
         let (in_self, out_other) = pipe()?;
         let (in_other, out_self) = pipe()?;
-        let (output_self, output_other) = pipe()?;
+        let (stdout_self, stdout_other) = pipe()?;
+        let (stderr_self, stderr_other) = pipe()?;
         let in_self_fd = in_self.as_raw_fd();
         let in_other_fd = in_other.as_raw_fd();
         let out_self_fd = out_self.as_raw_fd();
         let out_other_fd = out_other.as_raw_fd();
-        let output_other_fd = output_other.as_raw_fd();
-        let output_self_fd = output_self.as_raw_fd();
+        let stdout_other_fd = stdout_other.as_raw_fd();
+        let stdout_self_fd = stdout_self.as_raw_fd();
+        let stderr_other_fd = stderr_other.as_raw_fd();
+        let stderr_self_fd = stderr_self.as_raw_fd();
+        // the file system is shared, hence the socket is accessible from the node's namespace
+        let socket_path = workdir.path().join(format!("{}.sock", i));
+        let socket = UnixListener::bind(socket_path.as_path())?;
         let main = config.main.clone();
         let node_name = all_node_configs[i].name.clone();
         let all_node_configs = all_node_configs.clone();
@@ -193,62 +375,232 @@ fn do_network_switch_main<C: Into<NodeConfig>, F: FnOnce(Context) -> CallbackRes
                 unsafe {
                     OwnedFd::from_raw_fd(in_self_fd);
                     OwnedFd::from_raw_fd(out_self_fd);
-                    OwnedFd::from_raw_fd(output_self_fd);
+                    OwnedFd::from_raw_fd(stdout_self_fd);
+                    OwnedFd::from_raw_fd(stderr_self_fd);
+                    // the nodes are cloned, not executed, hence CLOEXEC does not apply
+                    OwnedFd::from_raw_fd(outcomes_fd);
                 }
-                network_node_main(
-                    in_other_fd,
-                    out_other_fd,
-                    output_other_fd,
-                    i,
-                    main,
-                    all_node_configs,
-                )
+                let fds = ChildFds {
+                    ipc_in: in_other_fd,
+                    ipc_out: out_other_fd,
+                    stdout: stdout_other_fd,
+                    stderr: stderr_other_fd,
+                };
+                network_node_main(fds, i, main, all_node_configs, socket_path)
             },
             STACK_SIZE,
             CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWUTS,
         )?;
+        trace_event!(debug, node = i, pid = %process.id(), "spawned node");
         // drop unused pipe ends
         drop(in_other);
         drop(out_other);
-        drop(output_other);
-        let pid_fd = process.fd()?;
-        ipc_fds.push((in_self, out_self, pid_fd, output_self, node_name));
+        drop(stdout_other);
+        drop(stderr_other);
+        ipc_fds.push(NodeFds {
+            ipc_in: in_self,
+            ipc_out: out_self,
+            pid: process.fd()?,
+            process_id: process.id(),
+            stdout: stdout_self,
+            stderr: stderr_self,
+            socket,
+            name: node_name,
+        });
         nodes.push(process);
     }
-    let mut ipc_server = IpcServer::new(ipc_fds)?;
-    ipc_server.run()?;
+    let node_pids: Vec<_> = ipc_fds
+        .iter()
+        .map(|fds| (fds.name.as_str(), fds.process_id))
+        .collect();
+    // the directory is removed when the nodes have exited
+    let _runtime_dir = match RuntimeDir::new(runtime_dir.as_path(), &node_pids) {
+        Ok(dir) => Some(dir),
+        Err(e) => {
+            log_format!(
+                warn: "WARNING: failed to publish the nodes in `{}`: {}",
+                runtime_dir.display(),
+                e
+            );
+            None
+        }
+    };
+    let output = &options.output;
+    let event_log = EventLog::new(
+        options.event_log.as_deref(),
+        options.trace.as_deref(),
+        start,
+    )?;
+    let mut ipc_server = IpcServer::new(ipc_fds, output, start, event_log)?;
+    let timeout = options.timeout;
+    let ret = ipc_server.run(timeout);
+    match ret {
+        Ok(ServerStop::Finished) => {}
+        Ok(ServerStop::NodeFailed(i)) => log_format!("node {} failed", i),
+        Ok(ServerStop::TimedOut) => ipc_server.log_event(LogEvent::Timeout),
+        Err(ref e) => log_format!("ipc server failed: {}", e),
+    }
+    let stopped_early = !matches!(ret, Ok(ServerStop::Finished));
+    if stopped_early {
+        if let Some(pause) = options.pause_on_failure {
+            pause_nodes(&nodes, &all_node_configs, pause);
+        }
+        for node in nodes.iter() {
+            let _ = node.kill(Signal::SIGTERM);
+        }
+    }
     let mut all_ret = Vec::with_capacity(nodes.len());
     for node in nodes.into_iter() {
         let status = node.wait()?;
         all_ret.push(status);
     }
-    if all_ret.iter().all(wait_status_ok) {
-        Ok(())
-    } else {
-        use std::fmt::Write;
-        let mut buf = String::with_capacity(4096);
-        writeln!(&mut buf, "some nodes failed:")?;
-        for (i, status) in all_ret.into_iter().enumerate() {
-            writeln!(
-                &mut buf,
-                "- node {} exited with {}",
-                i,
-                wait_status_to_string(status)
-            )?;
+    if ret.is_ok() {
+        ipc_server.receive_remaining()?;
+    }
+    for (i, status) in all_ret.iter().enumerate() {
+        trace_event!(info, node = i, status = %NodeStatus::from(*status), "node exited");
+        ipc_server.log_event(LogEvent::NodeExited {
+            node: i,
+            status: NodeStatus::from(*status).to_string(),
+        });
+    }
+    ipc_server.finish_event_log()?;
+    let mut all_outcomes = Vec::with_capacity(all_ret.len());
+    for (((node_config, status), state), output) in all_node_configs
+        .into_iter()
+        .zip(all_ret)
+        .zip(ipc_server.take_node_states())
+        .zip(ipc_server.take_output()?)
+    {
+        if let Some(error) = state.error {
+            // setup errors take precedence over node failures
+            return Err(error.into());
         }
-        Err(buf.into())
+        all_outcomes.push(NodeOutcome {
+            report: NodeReport {
+                name: node_config.name,
+                status: status.into(),
+                step: state.step,
+                step_name: state.step_name,
+                output,
+                interface_stats: state.interface_stats,
+            },
+            value: state.value,
+        });
+    }
+    if let Some(path) = options.junit.as_ref() {
+        write_junit(path, &all_outcomes, start.elapsed())?;
     }
+    if output.print == PrintMode::OnFailure
+        && (stopped_early || !all_outcomes.iter().all(NodeOutcome::is_success))
+    {
+        for (i, outcome) in all_outcomes.iter().enumerate() {
+            // the lines were received earlier, hence no timestamps
+            let prefix = Prefix::new(outcome.report.name.clone(), i, output.colors, None);
+            print_lines(&prefix, &outcome.report.output)?;
+        }
+    }
+    match (ret, timeout) {
+        (Ok(ServerStop::TimedOut), Some(timeout)) => Err(Error::Timeout(timeout)),
+        (Err(e), _) => Err(Error::Ipc(e.to_string())),
+        _ => Ok(all_outcomes),
+    }
+}
+
+/// The pipe ends that are passed to the node process.
+#[derive(Clone, Copy)]
+struct ChildFds {
+    ipc_in: RawFd,
+    ipc_out: RawFd,
+    stdout: RawFd,
+    stderr: RawFd,
 }
 
-fn network_node_main<F: FnOnce(Context) -> CallbackResult>(
-    ipc_in_fd: RawFd,
-    ipc_out_fd: RawFd,
-    output_fd: RawFd,
+/// Freeze the nodes until the user presses enter or the timeout expires.
+/// Assign the addresses from the subnet to the nodes that do not have one.
+///
+/// The addresses that are already assigned to other nodes are skipped.
+pub(crate) fn assign_addresses(
+    nodes: &mut [NodeConfig],
+    subnet: IpNet,
+) -> Result<(), std::io::Error> {
+    let mut used = HashSet::with_capacity(nodes.len());
+    for node in nodes.iter() {
+        let addr = node.ifaddr.addr();
+        if !addr.is_unspecified() && !used.insert(addr) {
+            return Err(format_error!(
+                "address {} is assigned to multiple nodes",
+                addr
+            ));
+        }
+    }
+    let mut hosts = subnet.hosts().filter(|addr| !used.contains(addr));
+    for node in nodes.iter_mut() {
+        if node.ifaddr.addr().is_unspecified() {
+            let addr = hosts
+                .next()
+                .ok_or_else(|| format_error!("subnet {} is too small", subnet))?;
+            node.ifaddr = IpNet::new(addr, subnet.prefix_len()).map_err(std::io::Error::other)?;
+        }
+    }
+    Ok(())
+}
+
+fn pause_nodes(nodes: &[Process], node_configs: &[NodeConfig], timeout: Duration) {
+    use std::fmt::Write;
+    // each node leads its own process group, hence the processes that it launched are stopped too
+    for node in nodes.iter() {
+        let _ = killpg(node.id(), Signal::SIGSTOP);
+    }
+    let id = Pid::this();
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut message = String::with_capacity(4096);
+    let _ = writeln!(message, "network {} is paused after the failure", id);
+    let _ = writeln!(message, "enter the nodes' namespaces with");
+    let _ = writeln!(message, "    testnet exec --network {} NODE -- COMMAND", id);
+    let _ = writeln!(message, "or");
+    let _ = writeln!(
+        message,
+        "    nsenter --target PID --net --uts --mount --user"
+    );
+    for (node, config) in nodes.iter().zip(node_configs.iter()) {
+        let _ = writeln!(message, "node `{}` PID {}", config.name, node.id());
+    }
+    if interactive {
+        let _ = writeln!(
+            message,
+            "press enter to terminate the nodes (or wait {:?})",
+            timeout
+        );
+    } else {
+        let _ = writeln!(message, "the nodes will be terminated in {:?}", timeout);
+    }
+    let _ = std::io::stderr().write_all(message.as_bytes());
+    if interactive {
+        let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        let _ = poll(&mut fds, timeout);
+    } else {
+        std::thread::sleep(timeout);
+    }
+    // stopped processes do not handle SIGTERM
+    for node in nodes.iter() {
+        let _ = killpg(node.id(), Signal::SIGCONT);
+    }
+}
+
+fn network_node_main<F: NodeMain>(
+    fds: ChildFds,
     i: usize,
     main: F,
     node_config: Vec<NodeConfig>,
-) -> c_int {
-    match do_network_node_main(ipc_in_fd, ipc_out_fd, output_fd, i, main, node_config) {
+    socket_path: PathBuf,
+) -> c_int
+where
+    F::Value: Encode,
+{
+    match do_network_node_main(fds, i, main, node_config, socket_path) {
         Ok(_) => 0,
         Err(e) => {
             log_format!("child `main` failed: {}", e);
@@ -257,24 +609,34 @@ fn network_node_main<F: FnOnce(Context) -> CallbackResult>(
     }
 }
 
-fn do_network_node_main<F: FnOnce(Context) -> CallbackResult>(
-    ipc_in_fd: RawFd,
-    ipc_out_fd: RawFd,
-    output_fd: RawFd,
+fn do_network_node_main<F: NodeMain>(
+    fds: ChildFds,
     i: usize,
     main: F,
     nodes: Vec<NodeConfig>,
-) -> CallbackResult {
+    socket_path: PathBuf,
+) -> CallbackResult
+where
+    F::Value: Encode,
+{
     // redirect stdout/stderr
-    dup2(output_fd, 1)?;
-    dup2(output_fd, 2)?;
+    dup2(fds.stdout, 1)?;
+    dup2(fds.stderr, 2)?;
+    let _span = trace_span!(INFO, "node", index = i, name = %nodes[i].name);
     // clonse stdin
     nix::unistd::close(0)?;
-    set_process_name(&nodes[i].name)?;
-    sethostname(&nodes[i].name)?;
-    configure_network(i, nodes[i].ifaddr)?;
-    let ipc_in_fd = unsafe { OwnedFd::from_raw_fd(ipc_in_fd) };
-    let ipc_out_fd = unsafe { OwnedFd::from_raw_fd(ipc_out_fd) };
+    let ipc_in_fd = unsafe { OwnedFd::from_raw_fd(fds.ipc_in) };
+    let ipc_out_fd = unsafe { OwnedFd::from_raw_fd(fds.ipc_out) };
+    // the context is consumed by `main`, hence we need a copy to send the value
+    let return_fd = ipc_out_fd.try_clone()?;
+    let mut outer_netlink = match setup_node(i, &nodes) {
+        Ok(netlink) => netlink,
+        Err(e) => {
+            let message = format!("node setup failed: {}", e);
+            send_message(&return_fd, &IpcMessage::Failed(e.into()))?;
+            return Err(message.into());
+        }
+    };
     let context = Context {
         node_index: i,
         nodes,
@@ -283,14 +645,51 @@ fn do_network_node_main<F: FnOnce(Context) -> CallbackResult>(
         step: 0,
         ifname: inner_ifname(i),
     };
-    main(context).map_err(|e| format!("node `main` failed: {}", e).into())
+    // the node process is single-threaded at this point
+    std::env::set_var(CONTEXT_ENV, context.to_env()?);
+    std::env::set_var(SOCKET_ENV, socket_path);
+    let result = main(context);
+    // the interfaces are removed when the node exits, hence the statistics are sent by the node
+    match veth_stats(i, &mut outer_netlink) {
+        Ok(stats) => send_message(&return_fd, &IpcMessage::Stats(stats))?,
+        Err(e) => log_format!("failed to read interface statistics: {}", e),
+    }
+    let value = result.map_err(|e| format!("node `main` failed: {}", e))?;
+    let value = encode_value(&value).map_err(std::io::Error::other)?;
+    send_message(&return_fd, &IpcMessage::Return(value))?;
+    Ok(())
+}
+
+/// Returns netlink socket in the switch's network namespace.
+fn setup_node(i: usize, nodes: &[NodeConfig]) -> Result<Netlink, Error> {
+    let _span = trace_span!(DEBUG, "setup");
+    set_process_name(&nodes[i].name)?;
+    sethostname(&nodes[i].name).map_err(|e| Error::Namespace(e.into()))?;
+    configure_network(i, &nodes[i])
+}
+
+fn veth_stats(i: usize, outer_netlink: &mut Netlink) -> Result<VethStats, Error> {
+    let mut netlink = Netlink::new(SockProtocol::NetlinkRoute)?;
+    Ok(VethStats {
+        inner: netlink.stats(inner_ifname(i))?,
+        outer: outer_netlink.stats(outer_ifname(i))?,
+    })
+}
+
+fn send_message(fd: &OwnedFd, message: &IpcMessage) -> Result<(), std::io::Error> {
+    let mut buf = Vec::new();
+    IpcEncodeDecode::encode(message, &mut buf).map_err(std::io::Error::other)?;
+    // async context makes the pipe non-blocking
+    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+    File::from(fd.try_clone()?).write_all(&buf)?;
+    Ok(())
 }
 
-fn configure_network(i: usize, ifaddr: IpNet) -> Result<(), std::io::Error> {
+fn configure_network(i: usize, node: &NodeConfig) -> Result<Netlink, Error> {
     let old_ns_file = File::open(format!("/proc/{}/ns/net", Pid::this()))?;
     let parent_ns_file = File::open(format!("/proc/{}/ns/net", Pid::parent()))?;
     // go back to parent's network namespace
-    setns(parent_ns_file, CloneFlags::CLONE_NEWNET)?;
+    setns(parent_ns_file, CloneFlags::CLONE_NEWNET).map_err(|e| Error::Namespace(e.into()))?;
     let mut netlink = Netlink::new(SockProtocol::NetlinkRoute)?;
     let bridge_index = netlink.index(BRIDGE_IFNAME)?;
     let inner = inner_ifname(i);
@@ -299,29 +698,22 @@ fn configure_network(i: usize, ifaddr: IpNet) -> Result<(), std::io::Error> {
     netlink.set_up(outer.clone())?;
     netlink.set_bridge(outer.clone(), bridge_index)?;
     netlink.set_network_namespace(inner.clone(), old_ns_file.as_raw_fd())?;
-    drop(netlink);
+    // the socket stays in the switch's namespace to read the statistics later
+    let outer_netlink = netlink;
     // go back to child's network namespace
-    setns(old_ns_file, CloneFlags::CLONE_NEWNET)?;
+    setns(old_ns_file, CloneFlags::CLONE_NEWNET).map_err(|e| Error::Namespace(e.into()))?;
     // we need new netlink socket because we changed ns
     let mut netlink = Netlink::new(SockProtocol::NetlinkRoute)?;
     netlink.set_up(LOOPBACK_IFNAME)?;
     let inner_index = netlink.index(inner.clone())?;
-    netlink.set_up(inner)?;
-    netlink.set_ifaddr(inner_index, ifaddr)?;
-    drop(netlink);
-    Ok(())
-}
-
-fn wait_status_ok(status: &WaitStatus) -> bool {
-    matches!(status, WaitStatus::Exited(_, code) if code == &0)
-}
-
-fn wait_status_to_string(status: WaitStatus) -> String {
-    match status {
-        WaitStatus::Exited(_, code) => format!("code {}", code),
-        WaitStatus::Signaled(_, signal, _) => format!("signal {:?}", signal),
-        _ => "unknown".to_string(),
+    netlink.set_up(inner.clone())?;
+    netlink.set_ifaddr(inner_index, node.ifaddr)?;
+    if !node.impairments.is_empty() {
+        netlink.set_impairments(inner_index, &node.impairments)?;
     }
+    drop(netlink);
+    trace_event!(debug, %inner, %outer, ifaddr = %node.ifaddr, "configured network interfaces");
+    Ok(outer_netlink)
 }
 
 fn outer_ifname(i: usize) -> String {
@@ -338,7 +730,15 @@ fn set_process_name(name: &str) -> Result<(), std::io::Error> {
     Ok(set_name(c_string.as_c_str())?)
 }
 
-const STACK_SIZE: usize = 4096 * 16;
+/// The outcomes of all the nodes or the error that occurred in the switch process.
+type SwitchResult = Result<Vec<NodeOutcome<Vec<u8>>>, RemoteError>;
+
+/// The stack size of the switch and node processes.
+///
+/// Nodes run user code (e.g. Tokio runtime or [`std::process::Command`]) on this stack,
+/// hence it matches the default stack size of the main thread.
+/// The memory is not touched until it is used.
+const STACK_SIZE: usize = 8 * 1024 * 1024;
 const BRIDGE_IFNAME: &str = "testnet";
 const SWITCH_NAME: &str = "switch";
 const LOOPBACK_IFNAME: &str = "lo";
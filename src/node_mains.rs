@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::CallbackResult;
+use crate::Context;
+
+/// Distinct `main` functions for the nodes.
+///
+/// The function is chosen by the node's name, then by its [role](crate::NodeConfig::role).
+/// The nodes that match neither use the fallback function.
+///
+/// ```rust,no_run
+/// use testnet::Network;
+/// use testnet::NodeConfig;
+/// use testnet::NodeMains;
+/// let main = NodeMains::new(|_context| Ok(()))
+///     .node("server", |_context| {
+///         // serve the clients
+///         Ok(())
+///     })
+///     .role("client", |context| {
+///         eprintln!("{} connects to the server", context.current_node_name());
+///         Ok(())
+///     });
+/// let network = Network::builder()
+///     .node("server")
+///     .nodes((0..3).map(|i| NodeConfig::new(format!("client{}", i)).with_role("client")))
+///     .build(main.into_main())
+///     .unwrap();
+/// network.wait().unwrap();
+/// ```
+pub struct NodeMains<'a, T> {
+    fallback: NodeMainFn<'a, T>,
+    by_name: HashMap<String, NodeMainFn<'a, T>>,
+    by_role: HashMap<String, NodeMainFn<'a, T>>,
+}
+
+impl<'a, T> NodeMains<'a, T> {
+    /// Create the functions with the specified fallback.
+    pub fn new<F: Fn(Context) -> CallbackResult<T> + 'a>(fallback: F) -> Self {
+        Self {
+            fallback: Rc::new(fallback),
+            by_name: Default::default(),
+            by_role: Default::default(),
+        }
+    }
+
+    /// Run `main` on the node with the specified name.
+    pub fn node<F: Fn(Context) -> CallbackResult<T> + 'a>(
+        mut self,
+        name: impl Into<String>,
+        main: F,
+    ) -> Self {
+        self.by_name.insert(name.into(), Rc::new(main));
+        self
+    }
+
+    /// Run `main` on the nodes with the specified role.
+    pub fn role<F: Fn(Context) -> CallbackResult<T> + 'a>(
+        mut self,
+        role: impl Into<String>,
+        main: F,
+    ) -> Self {
+        self.by_role.insert(role.into(), Rc::new(main));
+        self
+    }
+
+    /// Convert to the function that can be passed to [`NetConfig`](crate::NetConfig)
+    /// or [`NetworkBuilder`](crate::NetworkBuilder).
+    pub fn into_main(self) -> impl FnOnce(Context) -> CallbackResult<T> + Clone + 'a
+    where
+        T: 'a,
+    {
+        move |context| {
+            let node = context.current_node();
+            let main = self
+                .by_name
+                .get(node.name.as_str())
+                .or_else(|| match node.role.as_str() {
+                    "" => None,
+                    role => self.by_role.get(role),
+                })
+                .unwrap_or(&self.fallback)
+                .clone();
+            main(context)
+        }
+    }
+}
+
+impl<T> Clone for NodeMains<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            fallback: self.fallback.clone(),
+            by_name: self.by_name.clone(),
+            by_role: self.by_role.clone(),
+        }
+    }
+}
+
+type NodeMainFn<'a, T> = Rc<dyn Fn(Context) -> CallbackResult<T> + 'a>;
@@ -0,0 +1,137 @@
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use bincode::Decode;
+use bincode::Encode;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+
+use crate::VethStats;
+
+/// The outcome of the node's `main` function.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct NodeOutcome<T> {
+    /// How the node finished.
+    pub report: NodeReport,
+    /// The value returned by the node's `main` function.
+    ///
+    /// The value is `None` if `main` failed.
+    pub value: Option<T>,
+}
+
+impl<T> NodeOutcome<T> {
+    /// Returns `true` if the node process exited with zero code.
+    pub fn is_success(&self) -> bool {
+        self.report.status.is_success()
+    }
+}
+
+/// How the node finished.
+#[derive(Encode, Decode, Debug, Clone)]
+#[non_exhaustive]
+pub struct NodeReport {
+    /// Node name.
+    pub name: String,
+    /// How the node process exited.
+    pub status: NodeStatus,
+    /// The number of collective operations that the node has entered.
+    pub step: usize,
+    /// The name of the last step (see [`Context::step`](crate::Context::step)).
+    pub step_name: Option<String>,
+    /// The last lines of the node's output.
+    pub output: Vec<String>,
+    /// Interface statistics right before the node exited.
+    ///
+    /// The statistics are missing if the node process was killed or panicked.
+    pub interface_stats: Option<VethStats>,
+}
+
+impl Display for NodeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node `{}` exited with {}", self.name, self.status)?;
+        write!(f, ", last step {}", self.step)?;
+        if let Some(name) = self.step_name.as_ref() {
+            write!(f, " {:?}", name)?;
+        }
+        for line in self.output.iter() {
+            write!(f, "\n    {}: {}", self.name, line)?;
+        }
+        Ok(())
+    }
+}
+
+/// The error that is returned when some nodes failed.
+///
+/// Lists all the nodes including the ones that exited successfully.
+/// `Debug` is the same as `Display` to make `unwrap` output readable.
+pub struct NodeFailure {
+    /// The reports of all the nodes.
+    pub nodes: Vec<NodeReport>,
+}
+
+impl NodeFailure {
+    /// Returns the reports of the nodes that failed.
+    pub fn failed_nodes(&self) -> impl Iterator<Item = &NodeReport> {
+        self.nodes.iter().filter(|node| !node.status.is_success())
+    }
+}
+
+impl Display for NodeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "some nodes failed:")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            write!(f, "\n- {}: {}", i, node)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for NodeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for NodeFailure {}
+
+/// How the node process exited.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    /// The process exited with the specified code.
+    Exited(i32),
+    /// The process was terminated by the specified signal.
+    Signaled(i32),
+    /// The process status is not known.
+    Unknown,
+}
+
+impl NodeStatus {
+    /// Returns `true` if the process exited with zero code.
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Exited(0))
+    }
+}
+
+impl From<WaitStatus> for NodeStatus {
+    fn from(other: WaitStatus) -> Self {
+        match other {
+            WaitStatus::Exited(_, code) => Self::Exited(code),
+            WaitStatus::Signaled(_, signal, _) => Self::Signaled(signal as i32),
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl Display for NodeStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "code {}", code),
+            Self::Signaled(signal) => match Signal::try_from(*signal) {
+                Ok(signal) => write!(f, "signal {:?}", signal),
+                Err(_) => write!(f, "signal {}", signal),
+            },
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
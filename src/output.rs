@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::EventLog;
+use crate::LogEvent;
+use crate::OutputConfig;
+use crate::OutputRoute;
+use crate::PrintMode;
+
+/// Reads stdout and stderr of the node line by line.
+///
+/// Each line is routed according to the stream's configuration and written to the log file.
+/// The last lines of both streams are kept in the ring buffer.
+pub(crate) struct NodeOutput {
+    stdout: OutputReader,
+    stderr: OutputReader,
+    lines: LineWriter,
+}
+
+impl NodeOutput {
+    pub(crate) fn new(
+        stdout: OwnedFd,
+        stderr: OwnedFd,
+        node_index: usize,
+        prefix: Prefix,
+        config: &OutputConfig,
+        files: &mut OutputFiles,
+    ) -> Result<Self, std::io::Error> {
+        let log = match config.dir.as_ref() {
+            Some(dir) => Some(File::create(dir.join(format!("{}.log", prefix.name)))?),
+            None => None,
+        };
+        Ok(Self {
+            stdout: OutputReader::new(stdout, Sink::new(&config.stdout, config.print, files)?),
+            stderr: OutputReader::new(stderr, Sink::new(&config.stderr, config.print, files)?),
+            lines: LineWriter {
+                node_index,
+                prefix,
+                log,
+                buffer: VecDeque::with_capacity(config.buffer_len),
+                buffer_len: config.buffer_len,
+            },
+        })
+    }
+
+    /// Read all complete lines that are available without blocking.
+    pub(crate) fn read_lines(
+        &mut self,
+        stream: OutputStream,
+        events: &mut EventLog,
+    ) -> Result<(), std::io::Error> {
+        let reader = match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+        reader.read_lines(|sink, line| self.lines.write(sink, line, true, stream, events))
+    }
+
+    /// Read the output that remains after the node exited.
+    ///
+    /// The last line that does not end with a newline is handled as well.
+    pub(crate) fn read_remaining(&mut self, events: &mut EventLog) -> Result<(), std::io::Error> {
+        for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+            self.read_lines(stream, events)?;
+        }
+        for (stream, reader) in [
+            (OutputStream::Stdout, &mut self.stdout),
+            (OutputStream::Stderr, &mut self.stderr),
+        ] {
+            reader
+                .read_remaining(|sink, line| self.lines.write(sink, line, false, stream, events))?;
+        }
+        Ok(())
+    }
+
+    /// Take the last lines of the output.
+    pub(crate) fn take_buffer(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.lines.buffer).into()
+    }
+}
+
+/// Writes the lines of both streams to their destinations.
+struct LineWriter {
+    node_index: usize,
+    prefix: Prefix,
+    log: Option<File>,
+    /// Last lines of the output.
+    buffer: VecDeque<String>,
+    buffer_len: usize,
+}
+
+impl LineWriter {
+    /// Handle the line including the newline character.
+    ///
+    /// If the node did not terminate the last line, the reader adds the newline
+    /// and `terminated` is `false`. Only the printed line is marked with `⏎` then.
+    fn write(
+        &mut self,
+        sink: &mut Sink,
+        line: &[u8],
+        terminated: bool,
+        stream: OutputStream,
+        events: &mut EventLog,
+    ) -> Result<(), std::io::Error> {
+        match sink {
+            Sink::Stderr { print: true } => {
+                let mut stderr = std::io::stderr().lock();
+                self.prefix.write(&mut stderr)?;
+                if terminated {
+                    stderr.write_all(line)?;
+                } else {
+                    stderr.write_all(line.strip_suffix(b"\n").unwrap_or(line))?;
+                    stderr.write_all("⏎\n".as_bytes())?;
+                }
+            }
+            Sink::Stderr { print: false } => {}
+            Sink::File(file) => file.write_all(line)?,
+            Sink::Discard => return Ok(()),
+        }
+        if let Some(log) = self.log.as_mut() {
+            log.write_all(line)?;
+        }
+        if self.buffer_len == 0 && !events.is_enabled() {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(line)
+            .trim_end_matches('\n')
+            .to_string();
+        events.write(LogEvent::Output {
+            node: self.node_index,
+            stream: stream.as_str(),
+            line: &line,
+        });
+        if self.buffer_len != 0 {
+            if self.buffer.len() == self.buffer_len {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(line);
+        }
+        Ok(())
+    }
+}
+
+/// Output stream of the node.
+#[derive(Clone, Copy)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+/// The files that are shared by all the nodes.
+#[derive(Default)]
+pub(crate) struct OutputFiles {
+    files: Vec<(PathBuf, File)>,
+}
+
+impl OutputFiles {
+    fn get(&mut self, path: &PathBuf) -> Result<File, std::io::Error> {
+        if let Some((_, file)) = self.files.iter().find(|(p, _)| p == path) {
+            return file.try_clone();
+        }
+        let file = File::create(path)?;
+        let copy = file.try_clone()?;
+        self.files.push((path.clone(), file));
+        Ok(copy)
+    }
+}
+
+/// Where the lines of the stream go.
+enum Sink {
+    Stderr { print: bool },
+    File(File),
+    Discard,
+}
+
+impl Sink {
+    fn new(
+        route: &OutputRoute,
+        print: PrintMode,
+        files: &mut OutputFiles,
+    ) -> Result<Self, std::io::Error> {
+        Ok(match route {
+            OutputRoute::Stderr => Self::Stderr {
+                print: print == PrintMode::Always,
+            },
+            OutputRoute::File(path) => Self::File(files.get(path)?),
+            OutputRoute::Discard => Self::Discard,
+        })
+    }
+}
+
+struct OutputReader {
+    reader: BufReader<File>,
+    line: Vec<u8>,
+    sink: Sink,
+}
+
+impl OutputReader {
+    fn new(in_fd: OwnedFd, sink: Sink) -> Self {
+        Self {
+            reader: BufReader::with_capacity(OUTPUT_BUFFER_SIZE, in_fd.into()),
+            line: Vec::new(),
+            sink,
+        }
+    }
+
+    fn read_lines<F>(&mut self, mut on_line: F) -> Result<(), std::io::Error>
+    where
+        F: FnMut(&mut Sink, &[u8]) -> Result<(), std::io::Error>,
+    {
+        loop {
+            let mut buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let buf_len = buf.len();
+            if buf_len == 0 {
+                break;
+            }
+            while let Some(mut i) = buf.iter().position(|ch| *ch == b'\n') {
+                i += 1;
+                self.line.extend_from_slice(&buf[..i]);
+                on_line(&mut self.sink, &self.line)?;
+                self.line.clear();
+                buf = &buf[i..];
+            }
+            if !buf.is_empty() {
+                self.line.extend_from_slice(buf);
+            }
+            self.reader.consume(buf_len);
+        }
+        Ok(())
+    }
+
+    fn read_remaining<F>(&mut self, mut on_line: F) -> Result<(), std::io::Error>
+    where
+        F: FnMut(&mut Sink, &[u8]) -> Result<(), std::io::Error>,
+    {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            on_line(&mut self.sink, &self.line)?;
+            self.line.clear();
+        }
+        Ok(())
+    }
+}
+
+/// The prefix of each printed line.
+pub(crate) struct Prefix {
+    name: String,
+    color: Option<&'static str>,
+    start: Option<Instant>,
+}
+
+impl Prefix {
+    /// Create the prefix for the node with the specified index.
+    ///
+    /// The timestamps are relative to `start` and are omitted if it is `None`.
+    pub(crate) fn new(name: String, i: usize, colors: bool, start: Option<Instant>) -> Self {
+        let color = (colors && std::io::stderr().is_terminal()).then(|| COLORS[i % COLORS.len()]);
+        Self { name, color, start }
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<(), std::io::Error> {
+        if let Some(start) = self.start {
+            let elapsed = start.elapsed();
+            write!(
+                writer,
+                "[{:>4}.{:06}] ",
+                elapsed.as_secs(),
+                elapsed.subsec_micros()
+            )?;
+        }
+        match self.color {
+            Some(color) => write!(writer, "{color}{}{RESET}: ", self.name),
+            None => write!(writer, "{}: ", self.name),
+        }
+    }
+}
+
+/// Print the lines of the node's output with the node's prefix.
+pub(crate) fn print_lines(prefix: &Prefix, lines: &[String]) -> Result<(), std::io::Error> {
+    let mut stderr = std::io::stderr().lock();
+    for line in lines.iter() {
+        prefix.write(&mut stderr)?;
+        writeln!(stderr, "{line}")?;
+    }
+    Ok(())
+}
+
+const COLORS: [&str; 6] = [
+    "\x1b[31m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[35m", "\x1b[36m",
+];
+const RESET: &str = "\x1b[0m";
+
+pub(crate) const OUTPUT_BUFFER_SIZE: usize = 4096 * 16;
@@ -0,0 +1,304 @@
+use std::fs::DirBuilder;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use mio_pidfd::PidFd;
+use nix::errno::Errno;
+use nix::sched::setns;
+use nix::sched::CloneFlags;
+use nix::sys::signal::killpg;
+use nix::sys::signal::Signal;
+use nix::unistd::getuid;
+use nix::unistd::Pid;
+use nix::unistd::Uid;
+
+use crate::format_error;
+use crate::Error;
+
+/// Network that is running on this machine.
+///
+/// Every network publishes the process ids of its nodes in the runtime directory
+/// (`$XDG_RUNTIME_DIR/testnet-{uid}` or `/tmp/testnet-{uid}`) while it is running.
+/// Other processes use this directory to enter the nodes' namespaces.
+/// The directory must be owned by the current user and have mode 0700.
+pub struct RunningNetwork {
+    id: u32,
+    nodes: Vec<RunningNode>,
+}
+
+impl RunningNetwork {
+    /// List the running networks of the current user ordered by id.
+    ///
+    /// Fails if the runtime directory is not owned by the current user
+    /// or is accessible by other users.
+    pub fn list() -> Result<Vec<Self>, Error> {
+        let dir = runtime_dir(getuid());
+        match check_dir(dir.as_path()) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        }
+        let mut networks = Vec::new();
+        for entry in std::fs::read_dir(dir.as_path())? {
+            let entry = entry?;
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            check_dir(entry.path().as_path())?;
+            let nodes = match std::fs::read_to_string(entry.path().join(NODES_FILE)) {
+                Ok(nodes) => nodes,
+                // the nodes have not been spawned yet
+                Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let invalid = || format_error!("invalid nodes file in `{}`", entry.path().display());
+            let mut lines = nodes.lines();
+            let start_time: u64 = lines
+                .next()
+                .and_then(|line| line.parse().ok())
+                .ok_or_else(invalid)?;
+            // the directory is left behind if the switch process was killed,
+            // and its process id might have been reused since then
+            if process_start_time(Pid::from_raw(id as i32)).ok() != Some(start_time) {
+                continue;
+            }
+            let nodes = lines
+                .map(RunningNode::parse)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+            networks.push(Self { id, nodes });
+        }
+        networks.sort_unstable_by_key(|network| network.id);
+        Ok(networks)
+    }
+
+    /// Network id.
+    ///
+    /// This is the process id of the switch process.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// All the nodes of the network ordered by index.
+    pub fn nodes(&self) -> &[RunningNode] {
+        &self.nodes[..]
+    }
+
+    /// Terminate all the nodes of the network.
+    ///
+    /// The network exits when all its nodes have exited.
+    pub fn stop(&self) -> Result<(), Error> {
+        // the directory might have been replaced since it was listed
+        check_dir(runtime_dir(getuid()).join(self.id.to_string()).as_path())?;
+        for node in self.nodes.iter() {
+            // the node has exited and its process id might have been reused
+            if !node.is_running() {
+                continue;
+            }
+            // the node is the leader of its process group
+            match killpg(Pid::from_raw(node.pid as i32), Signal::SIGTERM) {
+                Ok(_) | Err(Errno::ESRCH) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the node by name or index.
+    pub fn node(&self, name_or_index: &str) -> Option<&RunningNode> {
+        self.nodes
+            .iter()
+            .find(|node| node.name == name_or_index)
+            .or_else(|| {
+                let i: usize = name_or_index.parse().ok()?;
+                self.nodes.get(i)
+            })
+    }
+}
+
+/// The node of the running network.
+pub struct RunningNode {
+    /// Node index.
+    pub index: usize,
+    /// Node name.
+    pub name: String,
+    /// Process id of the node.
+    pub pid: u32,
+    /// The start time of the node process that distinguishes it from the processes
+    /// that reuse its id.
+    start_time: u64,
+}
+
+impl RunningNode {
+    /// Move the current process to the node's user, network, UTS and mount namespaces.
+    ///
+    /// The current process must be single-threaded.
+    pub fn enter(&self) -> Result<(), Error> {
+        let pid_fd = PidFd::open(self.pid as i32, 0).map_err(Error::Namespace)?;
+        // the process id can not be reused while the descriptor is open
+        if !self.is_running() {
+            return Err(Error::Namespace(format_error!(
+                "node `{}` has exited",
+                self.name
+            )));
+        }
+        let mut flags =
+            CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNS;
+        // joining the current user namespace fails with EINVAL (e.g. in privileged mode)
+        let current_user_ns = std::fs::metadata("/proc/self/ns/user")?.ino();
+        let node_user_ns = std::fs::metadata(format!("/proc/{}/ns/user", self.pid))?.ino();
+        if current_user_ns != node_user_ns {
+            flags |= CloneFlags::CLONE_NEWUSER;
+        }
+        // changing the mount namespace resets the working directory
+        let cwd = std::env::current_dir().ok();
+        let pid_fd = unsafe { BorrowedFd::borrow_raw(pid_fd.as_raw_fd()) };
+        setns(pid_fd, flags).map_err(|e| Error::Namespace(e.into()))?;
+        if let Some(cwd) = cwd {
+            let _ = std::env::set_current_dir(cwd);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the process with the node's id is the node process.
+    fn is_running(&self) -> bool {
+        process_start_time(Pid::from_raw(self.pid as i32)).ok() == Some(self.start_time)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, ' ');
+        let index = fields.next()?.parse().ok()?;
+        let pid = fields.next()?.parse().ok()?;
+        let start_time = fields.next()?.parse().ok()?;
+        let name = fields.next()?.into();
+        Some(Self {
+            index,
+            name,
+            pid,
+            start_time,
+        })
+    }
+}
+
+/// Publishes the process ids of the nodes while the network is running.
+///
+/// The directory is removed on drop.
+pub(crate) struct RuntimeDir {
+    path: PathBuf,
+}
+
+impl RuntimeDir {
+    /// Create the network directory in the specified runtime directory.
+    ///
+    /// Should be called from the switch process.
+    pub(crate) fn new(dir: &Path, nodes: &[(&str, Pid)]) -> Result<Self, std::io::Error> {
+        create_dir(dir)?;
+        let path = dir.join(Pid::this().to_string());
+        // the directory of the previous network with the same id
+        if let Err(e) = std::fs::remove_dir_all(path.as_path()) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        create_dir(path.as_path())?;
+        let this = Self { path };
+        let mut contents = String::with_capacity(4096);
+        {
+            use std::fmt::Write;
+            let _ = writeln!(&mut contents, "{}", process_start_time(Pid::this())?);
+            for (i, (name, pid)) in nodes.iter().enumerate() {
+                let start_time = process_start_time(*pid)?;
+                let _ = writeln!(&mut contents, "{} {} {} {}", i, pid, start_time, name);
+            }
+        }
+        // readers see either no file or the complete file
+        let tmp = this.path.join(".nodes");
+        std::fs::write(tmp.as_path(), contents)?;
+        std::fs::rename(tmp.as_path(), this.path.join(NODES_FILE))?;
+        Ok(this)
+    }
+}
+
+impl Drop for RuntimeDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.path.as_path());
+    }
+}
+
+/// Returns the directory where the networks of the specified user are published.
+pub(crate) fn runtime_dir(uid: Uid) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/tmp"));
+    dir.join(format!("testnet-{}", uid))
+}
+
+/// Create the directory that is accessible only by the current user
+/// or check the existing one.
+fn create_dir(path: &Path) -> Result<(), std::io::Error> {
+    match DirBuilder::new().mode(0o700).create(path) {
+        Ok(_) => {
+            // the mode is masked by umask
+            std::fs::set_permissions(path, Permissions::from_mode(0o700))?;
+        }
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    check_dir(path)
+}
+
+/// Check that the directory is not a symbolic link, is owned by the current user
+/// and is accessible only by this user.
+///
+/// Otherwise other users could publish fake process ids or read the published ones.
+fn check_dir(path: &Path) -> Result<(), std::io::Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let insecure = |reason: &str| {
+        std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "insecure runtime directory `{}`: {}",
+                path.display(),
+                reason
+            ),
+        )
+    };
+    if !metadata.is_dir() {
+        return Err(insecure("not a directory"));
+    }
+    if metadata.uid() != getuid().as_raw() {
+        return Err(insecure("owned by another user"));
+    }
+    if metadata.mode() & 0o7777 != 0o700 {
+        return Err(insecure("mode is not 0700"));
+    }
+    Ok(())
+}
+
+/// Returns the start time of the process in clock ticks since boot.
+///
+/// Process ids are reused, but the pair of process id and start time is unique.
+fn process_start_time(pid: Pid) -> Result<u64, std::io::Error> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // the process name is enclosed in parentheses and may contain spaces
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(START_TIME_FIELD - 3))
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| format_error!("invalid `/proc/{}/stat`", pid))
+}
+
+const NODES_FILE: &str = "nodes";
+/// The number of `starttime` field in `/proc/<pid>/stat`, see `proc(5)`.
+const START_TIME_FIELD: usize = 22;
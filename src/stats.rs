@@ -0,0 +1,48 @@
+use bincode::Decode;
+use bincode::Encode;
+use netlink_packet_route::link::Stats64;
+
+/// Network interface statistics.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    /// Total bytes received.
+    pub rx_bytes: u64,
+    /// Total bytes transmitted.
+    pub tx_bytes: u64,
+    /// Total packets received.
+    pub rx_packets: u64,
+    /// Total packets transmitted.
+    pub tx_packets: u64,
+    /// Received packets that were dropped.
+    pub rx_dropped: u64,
+    /// Packets that were dropped on transmission.
+    pub tx_dropped: u64,
+    /// Bad packets received.
+    pub rx_errors: u64,
+    /// Packet transmit problems.
+    pub tx_errors: u64,
+}
+
+impl From<&Stats64> for InterfaceStats {
+    fn from(other: &Stats64) -> Self {
+        Self {
+            rx_bytes: other.rx_bytes,
+            tx_bytes: other.tx_bytes,
+            rx_packets: other.rx_packets,
+            tx_packets: other.tx_packets,
+            rx_dropped: other.rx_dropped,
+            tx_dropped: other.tx_dropped,
+            rx_errors: other.rx_errors,
+            tx_errors: other.tx_errors,
+        }
+    }
+}
+
+/// Statistics of both ends of the node's virtual Ethernet pair.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VethStats {
+    /// The end inside the node (`veth{i}`).
+    pub inner: InterfaceStats,
+    /// The end that is attached to the switch's bridge (`n{i}`).
+    pub outer: InterfaceStats,
+}
@@ -0,0 +1,134 @@
+use std::ffi::c_int;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+
+use nix::errno::Errno;
+use nix::sched::CloneFlags;
+use nix::sys::socket::SockProtocol;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::pipe;
+use nix::unistd::sethostname;
+use nix::unistd::Gid;
+use nix::unistd::Pid;
+use nix::unistd::Uid;
+
+use crate::pipe_channel;
+use crate::Error;
+use crate::Netlink;
+use crate::NodeStatus;
+use crate::PipeReceiver;
+use crate::Process;
+
+/// Check whether the network can be launched in the current environment.
+///
+/// Launches a short-lived process in the same namespaces as the network switch
+/// and tries to configure them.
+/// Returns [`Error::Unsupported`] with the reason if this fails
+/// (e.g. unprivileged user namespaces are disabled or restricted by the security module).
+pub fn is_supported() -> Result<(), Error> {
+    probe(false)
+}
+
+/// Same as [`is_supported`] but checks the namespaces that are created
+/// when [`NetOptions::privileged`](crate::NetOptions::privileged) is set.
+pub fn is_supported_privileged() -> Result<(), Error> {
+    probe(Uid::current().is_root())
+}
+
+pub(crate) fn probe(privileged: bool) -> Result<(), Error> {
+    let (sender, receiver) = pipe_channel()?;
+    let (errno_in, errno_out) = pipe()?;
+    let errno_in_fd = errno_in.as_raw_fd();
+    let errno_out_fd = errno_out.as_raw_fd();
+    let process = Process::spawn(
+        || {
+            // drop unused pipe end
+            unsafe { OwnedFd::from_raw_fd(errno_in_fd) };
+            probe_main(receiver.into(), errno_out_fd)
+        },
+        PROBE_STACK_SIZE,
+        namespace_flags(privileged),
+    )
+    .map_err(|e| Error::Unsupported(format!("failed to create namespaces: {}", e)))?;
+    // drop unused pipe end
+    drop(errno_out);
+    if !privileged {
+        write_id_maps(process.id())
+            .map_err(|e| Error::Unsupported(format!("failed to write uid/gid mappings: {}", e)))?;
+    }
+    // notify the child process
+    sender.close()?;
+    let mut buf = Vec::with_capacity(4);
+    File::from(errno_in).read_to_end(&mut buf)?;
+    let status = process.wait()?;
+    if let Ok(errno) = <[u8; 4]>::try_from(buf.as_slice()) {
+        return Err(Error::Unsupported(format!(
+            "failed to configure namespaces: {}",
+            Errno::from_raw(i32::from_le_bytes(errno))
+        )));
+    }
+    match status {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        status => Err(Error::Unsupported(format!(
+            "probe process exited with {}",
+            NodeStatus::from(status)
+        ))),
+    }
+}
+
+// Sends the error number to the parent process on failure.
+fn probe_main(receiver: PipeReceiver, errno_fd: RawFd) -> c_int {
+    let errno_out = unsafe { OwnedFd::from_raw_fd(errno_fd) };
+    let errno = match do_probe_main(receiver) {
+        Ok(_) => return 0,
+        Err(Error::Netlink(errno)) | Err(Error::Os(errno)) => errno,
+        Err(Error::Namespace(e)) | Err(Error::Io(e)) => {
+            e.raw_os_error().map(Errno::from_raw).unwrap_or(Errno::EIO)
+        }
+        Err(_) => Errno::EIO,
+    };
+    let _ = File::from(errno_out).write_all(&(errno as i32).to_le_bytes());
+    1
+}
+
+fn do_probe_main(receiver: PipeReceiver) -> Result<(), Error> {
+    // wait for uid/gid mappings to be done by the parent process
+    receiver.wait_until_closed()?;
+    sethostname(PROBE_NAME)?;
+    let mut netlink = Netlink::new(SockProtocol::NetlinkRoute)?;
+    netlink.new_bridge(PROBE_NAME)?;
+    Ok(())
+}
+
+pub(crate) fn namespace_flags(privileged: bool) -> CloneFlags {
+    let flags = CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNS;
+    if privileged {
+        flags
+    } else {
+        flags | CloneFlags::CLONE_NEWUSER
+    }
+}
+
+pub(crate) fn write_id_maps(pid: Pid) -> Result<(), std::io::Error> {
+    // update uid map
+    std::fs::write(
+        format!("/proc/{}/uid_map", pid),
+        format!("0 {} 1", Uid::current()),
+    )?;
+    // setgroups deny
+    std::fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+    // update gid map
+    std::fs::write(
+        format!("/proc/{}/gid_map", pid),
+        format!("0 {} 1", Gid::current()),
+    )?;
+    Ok(())
+}
+
+const PROBE_NAME: &str = "testnet-probe";
+const PROBE_STACK_SIZE: usize = 4096 * 16;
@@ -0,0 +1,51 @@
+use std::io::Write;
+use std::time::Duration;
+
+use crate::testnet_with_options;
+use crate::CallbackResult;
+use crate::Error;
+use crate::NetConfig;
+use crate::NetOptions;
+use crate::NodeConfig;
+use crate::NodeMain;
+
+/// Run the network for `#[testnet::test]` function.
+///
+/// Skips the test if Linux namespaces are not available and panics if the network fails.
+#[allow(clippy::panic)]
+pub fn run_test<F: NodeMain<Value = ()> + Clone>(nodes: usize, timeout: Duration, main: F) {
+    let config = NetConfig {
+        main,
+        nodes: vec![NodeConfig::default(); nodes],
+    };
+    let options = NetOptions {
+        timeout: Some(timeout),
+        skip_unsupported: true,
+        ..Default::default()
+    };
+    match testnet_with_options(config, options) {
+        Ok(_) => {}
+        Err(Error::Skipped(reason)) => {
+            let _ = writeln!(std::io::stderr(), "skipped: {}", reason);
+        }
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// The return types that `#[testnet::test]` function supports.
+pub trait TestResult {
+    /// Convert to the result of node's `main` function.
+    fn into_result(self) -> CallbackResult;
+}
+
+impl TestResult for () {
+    fn into_result(self) -> CallbackResult {
+        Ok(())
+    }
+}
+
+impl<E: Into<Box<dyn std::error::Error>>> TestResult for Result<(), E> {
+    fn into_result(self) -> CallbackResult {
+        self.map_err(Into::into)
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::LogEvent;
+
+/// Collects the spans of nodes, steps and collective operations
+/// and writes them in Chrome trace event format.
+///
+/// Each node is a thread of the same process in the trace.
+pub(crate) struct Trace {
+    path: PathBuf,
+    events: Vec<TraceEvent>,
+    /// Node name and launch time.
+    nodes: HashMap<usize, (String, f64)>,
+    /// Step name and start time.
+    steps: HashMap<usize, (String, f64)>,
+    /// Operation name and start time.
+    collectives: HashMap<usize, (&'static str, f64)>,
+}
+
+impl Trace {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            events: Vec::new(),
+            nodes: Default::default(),
+            steps: Default::default(),
+            collectives: Default::default(),
+        }
+    }
+
+    /// Update the spans.
+    ///
+    /// The time is in seconds since the network start.
+    pub(crate) fn on_event(&mut self, time: f64, event: &LogEvent<'_>) {
+        match event {
+            LogEvent::NodeSpawned { node, name, .. } => {
+                self.nodes.insert(*node, (name.to_string(), time));
+                self.events.push(TraceEvent::thread_name(*node, name));
+            }
+            LogEvent::NodeExited { node, status } => {
+                if let Some((name, start)) = self.steps.remove(node) {
+                    self.push_span(name, "step", *node, start, time, Some(status));
+                }
+                if let Some((name, start)) = self.collectives.remove(node) {
+                    self.push_span(name.into(), "collective", *node, start, time, Some(status));
+                }
+                if let Some((name, start)) = self.nodes.remove(node) {
+                    self.push_span(name, "node", *node, start, time, Some(status));
+                }
+            }
+            LogEvent::Step { node, name } => {
+                // the previous step has not entered any collective operation
+                if let Some((name, start)) = self.steps.remove(node) {
+                    self.push_span(name, "step", *node, start, time, None);
+                }
+                self.steps.insert(*node, (name.to_string(), time));
+            }
+            LogEvent::CollectiveEnter {
+                node, operation, ..
+            } => {
+                self.collectives.insert(*node, (operation, time));
+            }
+            LogEvent::Collective { participants, .. } => {
+                // the step ends when the collective operation finishes on all the nodes
+                for node in participants.iter() {
+                    if let Some((name, start)) = self.collectives.remove(node) {
+                        self.push_span(name.into(), "collective", *node, start, time, None);
+                    }
+                    if let Some((name, start)) = self.steps.remove(node) {
+                        self.push_span(name, "step", *node, start, time, None);
+                    }
+                }
+            }
+            LogEvent::Output { .. } | LogEvent::Message { .. } | LogEvent::Timeout => {}
+        }
+    }
+
+    /// Write the trace to the file.
+    pub(crate) fn write(self) -> Result<(), std::io::Error> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(
+            &mut writer,
+            &TraceFile {
+                trace_events: self.events,
+            },
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn push_span(
+        &mut self,
+        name: String,
+        category: &'static str,
+        node: usize,
+        start: f64,
+        end: f64,
+        status: Option<&String>,
+    ) {
+        self.events.push(TraceEvent {
+            name,
+            cat: category,
+            ph: "X",
+            ts: start * 1e6,
+            dur: Some((end - start) * 1e6),
+            pid: 0,
+            tid: node,
+            args: status.map(|status| TraceArgs {
+                status: Some(status.clone()),
+                name: None,
+            }),
+        });
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceFile {
+    trace_events: Vec<TraceEvent>,
+}
+
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    /// Start time in microseconds.
+    ts: f64,
+    /// Duration in microseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<TraceArgs>,
+}
+
+impl TraceEvent {
+    fn thread_name(node: usize, name: &str) -> Self {
+        Self {
+            name: "thread_name".into(),
+            cat: "__metadata",
+            ph: "M",
+            ts: 0.0,
+            dur: None,
+            pid: 0,
+            tid: node,
+            args: Some(TraceArgs {
+                name: Some(name.into()),
+                status: None,
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TraceArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
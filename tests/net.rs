@@ -1,11 +1,40 @@
 #![allow(clippy::unwrap_used)]
 #![allow(clippy::panic)]
+#![allow(missing_docs)]
 
+use std::fs::Permissions;
+use std::io::Read;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::os::fd::BorrowedFd;
+use std::os::fd::RawFd;
+use std::os::unix::fs::PermissionsExt;
 use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
 
+use nix::errno::Errno;
+use nix::unistd::getuid;
+use nix::unistd::write;
+use serde_json::json;
+
+use testnet::command_main;
 use testnet::testnet;
+use testnet::testnet_with_options;
+use testnet::Error;
+use testnet::Impairments;
 use testnet::NetConfig;
+use testnet::NetOptions;
+use testnet::Network;
 use testnet::NodeConfig;
+use testnet::NodeMains;
+use testnet::NodeStatus;
+use testnet::OutputConfig;
+use testnet::OutputRoute;
+use testnet::PrintMode;
+use testnet::RunningNetwork;
+use testnet::CONTEXT_ENV;
 
 #[test]
 fn net2() {
@@ -81,6 +110,1076 @@ fn broadcast_all() {
     testnet(config).unwrap();
 }
 
+#[test]
+fn broadcast_all_large() {
+    let config = NetConfig {
+        main: |mut context| {
+            let i = context.current_node_index();
+            // larger than the pipe buffer
+            let data = vec![i as u8; 4096 * 64];
+            let all_data = context.broadcast_all(data)?;
+            assert_eq!(3, all_data.len());
+            for (i, data) in all_data.into_iter().enumerate() {
+                assert_eq!(4096 * 64, data.len());
+                assert!(data.iter().all(|x| *x == i as u8));
+            }
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 3],
+    };
+    testnet(config).unwrap();
+}
+
+#[test]
+fn key_value() {
+    let config = NetConfig {
+        main: |mut context| {
+            match context.current_node_index() {
+                0 => context.kv_put("key", b"value".to_vec())?,
+                _ => assert_eq!(b"value".to_vec(), context.kv_get("key")?),
+            }
+            context.broadcast_all(Vec::new())?;
+            if context.current_node_index() == 1 {
+                context.kv_put("key", b"overwritten".to_vec())?;
+            }
+            context.broadcast_all(Vec::new())?;
+            assert_eq!(b"overwritten".to_vec(), context.kv_get("key")?);
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 3],
+    };
+    testnet(config).unwrap();
+}
+
+#[test]
+fn socket_protocol() {
+    let config = NetConfig {
+        main: |mut context| {
+            if context.current_node_index() == 1 {
+                let all_data = context.broadcast_all(b"rust".to_vec())?;
+                assert_eq!(vec![b"socket".to_vec(), b"rust".to_vec()], all_data);
+                assert_eq!(b"value".to_vec(), context.kv_get("key")?);
+                return Ok(());
+            }
+            let path = std::env::var("TESTNET_SOCKET")?;
+            let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+            let response = socket_request(&mut stream, json!({"type": "hello", "version": 1}))?;
+            assert_eq!(json!({"type": "hello", "version": 1}), response);
+            let response = socket_request(
+                &mut stream,
+                json!({"type": "broadcast_all", "data": "socket"}),
+            )?;
+            assert_eq!(
+                json!({"type": "all_data", "data": ["socket", "rust"]}),
+                response
+            );
+            let response = socket_request(
+                &mut stream,
+                json!({"type": "kv_put", "key": "key", "value": "value"}),
+            )?;
+            assert_eq!(json!({"type": "ok"}), response);
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let options = NetOptions::default().with_timeout(Duration::from_secs(10));
+    testnet_with_options(config, options).unwrap();
+}
+
+#[test]
+fn socket_disconnect() {
+    let config = NetConfig {
+        main: |mut context| {
+            if context.current_node_index() == 1 {
+                // let the socket client disconnect first
+                std::thread::sleep(Duration::from_millis(200));
+                context.broadcast_all(b"x".to_vec())?;
+                context.kv_put("done", Vec::new())?;
+            } else {
+                let path = std::env::var("TESTNET_SOCKET")?;
+                let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+                socket_request(&mut stream, json!({"type": "hello", "version": 1}))?;
+                let request = json!({"type": "barrier", "name": "x"}).to_string();
+                stream.write_all(&(request.len() as u32).to_le_bytes())?;
+                stream.write_all(request.as_bytes())?;
+                // the client is gone before the barrier is passed
+                drop(stream);
+                context.kv_get("done")?;
+            }
+            let all_data =
+                context.broadcast_all_string(context.current_node_index().to_string())?;
+            assert_eq!(vec!["0", "1"], all_data);
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let options = NetOptions::default().with_timeout(Duration::from_secs(10));
+    testnet_with_options(config, options).unwrap();
+}
+
+fn socket_request(
+    stream: &mut std::os::unix::net::UnixStream,
+    request: serde_json::Value,
+) -> Result<serde_json::Value, std::io::Error> {
+    let request = request.to_string();
+    stream.write_all(&(request.len() as u32).to_le_bytes())?;
+    stream.write_all(request.as_bytes())?;
+    let mut len = [0_u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0_u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(serde_json::from_slice(&response)?)
+}
+
+#[test]
+fn builder() {
+    let network = Network::builder()
+        .nodes(["a", "b"])
+        .node(NodeConfig::new("c").with_ifaddr("10.1.0.100/24".parse().unwrap()))
+        .subnet("10.1.0.0/24".parse().unwrap())
+        .timeout(Duration::from_secs(10))
+        .build(|context| Ok(context.current_node().ifaddr.to_string()))
+        .unwrap();
+    let outcomes = network.wait().unwrap();
+    let addresses: Vec<_> = outcomes
+        .into_iter()
+        .map(|outcome| {
+            assert!(outcome.is_success());
+            outcome.value.unwrap()
+        })
+        .collect();
+    assert_eq!(
+        vec!["10.1.0.1/24", "10.1.0.2/24", "10.1.0.100/24"],
+        addresses
+    );
+}
+
+#[test]
+fn node_mains() {
+    let main = NodeMains::new(|_context| Ok("fallback".to_string()))
+        .node("server", |_context| Ok("server".to_string()))
+        .role("client", |context| {
+            Ok(format!("client {}", context.current_node_name()))
+        });
+    let config = NetConfig {
+        main: main.into_main(),
+        nodes: vec![
+            NodeConfig::new("server"),
+            NodeConfig::new("c1").with_role("client"),
+            NodeConfig::new("c2").with_role("client"),
+            NodeConfig::new("other").with_role("unknown"),
+        ],
+    };
+    let values: Vec<_> = testnet(config)
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.value.unwrap())
+        .collect();
+    assert_eq!(vec!["server", "client c1", "client c2", "fallback"], values);
+}
+
+#[test]
+fn commands() {
+    let config = NetConfig {
+        main: command_main(|context| {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_testnet"));
+            command.args(["broadcast-all", context.current_node_name()]);
+            command
+        }),
+        // the names are passed to the command in the environment
+        nodes: vec!["a b", "c=d"],
+    };
+    let options = NetOptions::default().with_timeout(Duration::from_secs(10));
+    let outcomes = testnet_with_options(config, options).unwrap();
+    for outcome in outcomes.iter() {
+        assert_eq!(vec!["a b", "c=d"], outcome.report.output);
+    }
+    let config = NetConfig {
+        main: command_main(|_context| Command::new("false")),
+        nodes: vec!["a"],
+    };
+    match testnet(config).unwrap_err() {
+        Error::NodeFailure(failure) => assert_eq!(1, failure.failed_nodes().count()),
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn large_stack() {
+    let config = NetConfig {
+        main: |_context| {
+            // larger than the thread stack of the test harness
+            let buf = [1_u8; 4 * 1024 * 1024];
+            assert_eq!(
+                buf.len(),
+                std::hint::black_box(&buf)
+                    .iter()
+                    .map(|x| *x as usize)
+                    .sum::<usize>()
+            );
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    testnet(config).unwrap();
+}
+
+#[test]
+fn return_values() {
+    let config = NetConfig {
+        main: |context| Ok(context.current_node_index() * 10),
+        nodes: vec!["node1", "node2"],
+    };
+    let outcomes = testnet(config).unwrap();
+    assert_eq!(2, outcomes.len());
+    for (i, outcome) in outcomes.into_iter().enumerate() {
+        assert!(outcome.is_success());
+        assert_eq!(format!("node{}", i + 1), outcome.report.name);
+        assert_eq!(Some(i * 10), outcome.value);
+    }
+}
+
+#[test]
+fn failure_report() {
+    let config = NetConfig {
+        main: |mut context| {
+            context.step("first");
+            context.broadcast_all(Vec::new())?;
+            if context.current_node_index() == 1 {
+                context.step("second");
+                // `eprintln` output is captured by the test harness
+                writeln!(std::io::stderr(), "something went wrong")?;
+                return Err("node failed".into());
+            }
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let failure = match testnet(config).unwrap_err() {
+        Error::NodeFailure(failure) => failure,
+        other => panic!("unexpected error: {}", other),
+    };
+    assert_eq!(2, failure.nodes.len());
+    let failed: Vec<_> = failure.failed_nodes().collect();
+    assert_eq!(1, failed.len());
+    assert_eq!("node2", failed[0].name);
+    assert_eq!(NodeStatus::Exited(1), failed[0].status);
+    assert_eq!(1, failed[0].step);
+    assert_eq!(Some("second"), failed[0].step_name.as_deref());
+    assert!(failed[0]
+        .output
+        .iter()
+        .any(|line| line == "something went wrong"));
+}
+
+#[test]
+fn step_index() {
+    let config = NetConfig {
+        main: |mut context| {
+            match context.current_node_index() {
+                0 => context.broadcast_one().send_string("ping".into())?,
+                1 => {
+                    context.broadcast_one().recv()?;
+                }
+                _ => context.broadcast_one().wait()?,
+            }
+            context.broadcast_all(Vec::new())?;
+            Ok(())
+        },
+        nodes: vec!["send", "recv", "wait"],
+    };
+    for outcome in testnet(config).unwrap() {
+        assert_eq!(2, outcome.report.step, "node {}", outcome.report.name);
+    }
+}
+
+#[test]
+fn timeout() {
+    let config = NetConfig {
+        main: |context| {
+            if context.current_node_index() == 1 {
+                std::thread::sleep(Duration::from_secs(10));
+            }
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let options = NetOptions::default().with_timeout(Duration::from_millis(500));
+    match testnet_with_options(config, options).unwrap_err() {
+        Error::Timeout(timeout) => assert_eq!(Duration::from_millis(500), timeout),
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn ipc_error() {
+    let config = NetConfig {
+        main: |mut context| {
+            if context.current_node_index() == 0 {
+                let env = std::env::var(CONTEXT_ENV).map_err(std::io::Error::other)?;
+                let env: serde_json::Value =
+                    serde_json::from_str(&env).map_err(std::io::Error::other)?;
+                let fd = env["output_fd"].as_i64().unwrap_or_default() as RawFd;
+                // a frame that is larger than the maximum message size
+                write(unsafe { BorrowedFd::borrow_raw(fd) }, &[0xff; 4]).map_err(Error::Os)?;
+            }
+            context.broadcast_all(Vec::new())?;
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let options = NetOptions::default().with_timeout(Duration::from_secs(10));
+    match testnet_with_options(config, options).unwrap_err() {
+        Error::Ipc(..) => {}
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn pause_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let child_pid_path = dir.path().join("child.pid");
+    let watcher = std::thread::spawn({
+        let child_pid_path = child_pid_path.clone();
+        move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5) {
+                let networks = RunningNetwork::list().unwrap();
+                let node = networks.iter().find_map(|network| network.node("paused1"));
+                let child_pid = std::fs::read_to_string(child_pid_path.as_path()).ok();
+                if let (Some(node), Some(child_pid)) = (node, child_pid) {
+                    // the children of the node are stopped as well
+                    if process_state(node.pid) == Some('T')
+                        && process_state(child_pid.parse().unwrap()) == Some('T')
+                    {
+                        return true;
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            false
+        }
+    });
+    let config = NetConfig {
+        main: move |context| {
+            if context.current_node_index() == 1 {
+                let child = Command::new("sleep").arg("10").spawn()?;
+                std::fs::write(child_pid_path, child.id().to_string())?;
+                std::thread::sleep(Duration::from_secs(10));
+            }
+            Ok(())
+        },
+        nodes: vec!["paused0", "paused1"],
+    };
+    let options = NetOptions::default()
+        .with_timeout(Duration::from_millis(500))
+        .with_pause_on_failure(Duration::from_secs(2));
+    let start = Instant::now();
+    assert!(matches!(
+        testnet_with_options(config, options),
+        Err(Error::Timeout(..))
+    ));
+    assert!(start.elapsed() >= Duration::from_secs(2));
+    assert!(watcher.join().unwrap(), "the node was not stopped");
+}
+
+fn process_state(pid: u32) -> Option<char> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the state follows the process name in parentheses
+    stat.rsplit_once(") ")?.1.chars().next()
+}
+
+#[test]
+fn output_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = NetConfig {
+        main: |context| {
+            let mut stdout = std::io::stdout();
+            for i in 0..3 {
+                writeln!(stdout, "{}: line {}", context.current_node_name(), i)?;
+            }
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let options = NetOptions::default().with_output(
+        OutputConfig::default()
+            .with_print(PrintMode::OnFailure)
+            .with_dir(dir.path())
+            .with_buffer_len(2),
+    );
+    let outcomes = testnet_with_options(config, options).unwrap();
+    for outcome in outcomes.into_iter() {
+        let name = outcome.report.name;
+        let log = std::fs::read_to_string(dir.path().join(format!("{}.log", name))).unwrap();
+        assert_eq!(
+            format!("{name}: line 0\n{name}: line 1\n{name}: line 2\n"),
+            log
+        );
+        assert_eq!(
+            vec![format!("{name}: line 1"), format!("{name}: line 2")],
+            outcome.report.output
+        );
+    }
+}
+
+#[test]
+fn invalid_node_name() {
+    let config = NetConfig {
+        main: |_context| Ok(()),
+        nodes: vec!["../node1", "node2"],
+    };
+    match testnet(config).unwrap_err() {
+        Error::Io(e) => assert!(e.to_string().contains("invalid node name"), "{}", e),
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn output_routes() {
+    let dir = tempfile::tempdir().unwrap();
+    let stdout_path = dir.path().join("stdout");
+    let config = NetConfig {
+        main: |context| {
+            let name = context.current_node_name();
+            writeln!(std::io::stdout(), "{} stdout", name)?;
+            writeln!(std::io::stderr(), "{} stderr", name)?;
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let options = NetOptions::default().with_output(
+        OutputConfig::default()
+            .with_stdout(OutputRoute::File(stdout_path.clone()))
+            .with_stderr(OutputRoute::Discard),
+    );
+    let outcomes = testnet_with_options(config, options).unwrap();
+    let mut lines: Vec<_> = std::fs::read_to_string(stdout_path)
+        .unwrap()
+        .lines()
+        .map(ToString::to_string)
+        .collect();
+    lines.sort();
+    assert_eq!(vec!["node1 stdout", "node2 stdout"], lines);
+    for outcome in outcomes.into_iter() {
+        let name = outcome.report.name;
+        assert_eq!(vec![format!("{name} stdout")], outcome.report.output);
+    }
+}
+
+#[test]
+fn unterminated_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let stdout_path = dir.path().join("stdout");
+    let config = NetConfig {
+        main: |_context| {
+            // `print!` would be captured by the test harness
+            let mut stdout = std::io::stdout();
+            write!(stdout, "x")?;
+            stdout.flush()?;
+            Ok(())
+        },
+        nodes: vec!["node1"],
+    };
+    let options = NetOptions::default()
+        .with_output(OutputConfig::default().with_stdout(OutputRoute::File(stdout_path.clone())));
+    let outcomes = testnet_with_options(config, options).unwrap();
+    assert_eq!("x\n", std::fs::read_to_string(stdout_path).unwrap());
+    assert_eq!(vec!["x"], outcomes[0].report.output);
+}
+
+#[test]
+fn event_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("events.jsonl");
+    let config = NetConfig {
+        main: |mut context| {
+            context.step("exchange");
+            let data = "x".repeat(context.current_node_index() + 1);
+            context.broadcast_all_string(data)?;
+            writeln!(std::io::stdout(), "done")?;
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let options = NetOptions::default().with_event_log(path.clone());
+    testnet_with_options(config, options).unwrap();
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let count = |name: &str| events.iter().filter(|e| e["event"] == name).count();
+    assert_eq!(2, count("node_spawned"));
+    assert_eq!(2, count("node_exited"));
+    assert_eq!(2, count("step"));
+    assert_eq!(2, count("collective_enter"));
+    let collective = events.iter().find(|e| e["event"] == "collective").unwrap();
+    assert_eq!("broadcast_all", collective["operation"]);
+    assert_eq!(serde_json::json!([1, 2]), collective["payload_sizes"]);
+    assert!(events
+        .iter()
+        .any(|e| e["event"] == "output" && e["stream"] == "stdout" && e["line"] == "done"));
+    assert!(events.iter().all(|e| e["time"].is_f64()));
+}
+
+#[test]
+fn chrome_trace() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace.json");
+    let config = NetConfig {
+        main: |mut context| {
+            context.step("exchange");
+            if context.current_node_index() == 1 {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            context.broadcast_all(Vec::new())?;
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let options = NetOptions::default().with_trace(path.clone());
+    testnet_with_options(config, options).unwrap();
+    let trace: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+    let span = |cat: &str, tid: usize| {
+        events
+            .iter()
+            .find(|e| e["ph"] == "X" && e["cat"] == cat && e["tid"] == tid)
+            .unwrap()
+    };
+    for i in 0..2 {
+        assert_eq!("exchange", span("step", i)["name"]);
+        assert_eq!(format!("node{}", i + 1), span("node", i)["name"]);
+    }
+    // the first node waited for the second one
+    let waited = span("collective", 0)["dur"].as_f64().unwrap();
+    assert!(waited >= 90_000.0, "waited {}us", waited);
+    assert!(span("collective", 1)["dur"].as_f64().unwrap() < waited);
+}
+
+#[test]
+fn junit_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("junit.xml");
+    let config = NetConfig {
+        main: |context| {
+            if context.current_node_index() == 1 {
+                writeln!(std::io::stderr(), "<bad> & \"worse\"")?;
+                return Err("node failed".into());
+            }
+            Ok(())
+        },
+        nodes: vec!["node1", "node2"],
+    };
+    let options = NetOptions::default().with_junit(path.clone());
+    assert!(testnet_with_options(config, options).is_err());
+    let xml = std::fs::read_to_string(path).unwrap();
+    assert!(xml.contains(r#"<testsuite name="testnet" tests="2" failures="1""#));
+    assert!(xml.contains(r#"<testcase classname="testnet" name="node1"/>"#));
+    assert!(xml.contains(r#"<failure message="exited with code 1, last step 0">"#));
+    assert!(xml.contains("&lt;bad&gt; &amp; &quot;worse&quot;"));
+}
+
+#[test]
+fn interface_stats() {
+    let config = NetConfig {
+        main: |mut context| {
+            let socket = UdpSocket::bind((context.current_node().ifaddr.addr(), 9000))?;
+            let before = context.interface_stats()?;
+            // wait until all sockets are bound
+            context.broadcast_all(Vec::new())?;
+            if context.current_node_index() == 0 {
+                let peer = context.node(1).ifaddr.addr();
+                socket.send_to(&[0_u8; 1000], (peer, 9000))?;
+                let after = context.interface_stats()?;
+                assert!(after.tx_bytes - before.tx_bytes >= 1000);
+                assert!(after.tx_packets > before.tx_packets);
+            } else {
+                let mut buf = [0_u8; 2000];
+                let (n, _) = socket.recv_from(&mut buf)?;
+                assert_eq!(1000, n);
+            }
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let outcomes = testnet(config).unwrap();
+    for outcome in outcomes.into_iter() {
+        let stats = outcome.report.interface_stats.unwrap();
+        // the outer end receives what the inner end transmits
+        assert_eq!(stats.inner.tx_bytes, stats.outer.rx_bytes);
+        assert_eq!(stats.inner.rx_bytes, stats.outer.tx_bytes);
+    }
+}
+
+#[test]
+fn impairments() {
+    let config = NetConfig {
+        main: |mut context| {
+            let socket = UdpSocket::bind((context.current_node().ifaddr.addr(), 9000))?;
+            context.broadcast_all(Vec::new())?;
+            let mut buf = [0_u8; 16];
+            if context.current_node_index() == 0 {
+                let start = Instant::now();
+                socket.send_to(b"ping", (context.node(1).ifaddr.addr(), 9000))?;
+                socket.recv_from(&mut buf)?;
+                assert!(start.elapsed() >= Duration::from_millis(50));
+            } else {
+                let (_, from) = socket.recv_from(&mut buf)?;
+                socket.send_to(b"pong", from)?;
+            }
+            Ok(())
+        },
+        nodes: vec![
+            NodeConfig {
+                impairments: Impairments {
+                    delay: Duration::from_millis(50),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            NodeConfig::default(),
+        ],
+    };
+    // the second node waits forever if the first one fails
+    let options = NetOptions::default().with_timeout(Duration::from_secs(5));
+    match testnet_with_options(config, options) {
+        Ok(_) => {}
+        Err(Error::Netlink(Errno::ENOENT)) => eprintln!("netem is not available"),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+fn address_conflicts() {
+    // the subnet of the builder
+    let network = Network::builder()
+        .nodes(["a", "b"])
+        .node(NodeConfig::new("c").with_ifaddr("10.1.0.1/24".parse().unwrap()))
+        .subnet("10.1.0.0/24".parse().unwrap())
+        .build(|context| Ok(context.current_node().ifaddr.to_string()))
+        .unwrap();
+    let addresses: Vec<_> = network
+        .wait()
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.value.unwrap())
+        .collect();
+    assert_eq!(vec!["10.1.0.2/24", "10.1.0.3/24", "10.1.0.1/24"], addresses);
+    // the default subnet
+    let config = NetConfig {
+        main: |context| Ok(context.current_node().ifaddr.to_string()),
+        nodes: vec![
+            NodeConfig::default(),
+            NodeConfig::default().with_ifaddr("10.84.0.1/16".parse().unwrap()),
+        ],
+    };
+    let addresses: Vec<_> = testnet(config)
+        .unwrap()
+        .into_iter()
+        .map(|outcome| outcome.value.unwrap())
+        .collect();
+    assert_eq!(vec!["10.84.0.2/16", "10.84.0.1/16"], addresses);
+    // the same explicit address
+    let config = NetConfig {
+        main: |_context| Ok(()),
+        nodes: vec![NodeConfig::default().with_ifaddr("10.84.0.1/16".parse().unwrap()); 2],
+    };
+    match testnet(config).unwrap_err() {
+        Error::Io(e) => assert!(e.to_string().contains("multiple nodes"), "{}", e),
+        other => panic!("unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn cli_topology() {
+    let dir = tempfile::tempdir().unwrap();
+    let topology = dir.path().join("topology.toml");
+    let stdout = dir.path().join("stdout");
+    std::fs::write(
+        &topology,
+        r#"
+command = "sh"
+args = ["-c", "echo $TESTNET_NODE_NAME $TESTNET_NODE_IFADDR $ROLE"]
+env = { ROLE = "worker" }
+
+[[address_pools]]
+name = "dc1"
+subnet = "10.1.0.0/24"
+
+[[nodes]]
+name = "seed"
+address = "10.1.0.100/24"
+env = { ROLE = "seed" }
+
+[[nodes]]
+name = "worker"
+address_pool = "dc1"
+"#,
+    )
+    .unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .arg("--topology")
+        .arg(&topology)
+        .arg("--stdout")
+        .arg(&stdout)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut lines: Vec<String> = std::fs::read_to_string(&stdout)
+        .unwrap()
+        .lines()
+        .map(Into::into)
+        .collect();
+    lines.sort();
+    assert_eq!(
+        vec!["seed 10.1.0.100/24 seed", "worker 10.1.0.1/24 worker"],
+        lines
+    );
+    // the pool skips the explicit addresses
+    std::fs::write(
+        &topology,
+        r#"
+command = "sh"
+args = ["-c", "echo $TESTNET_NODE_NAME $TESTNET_NODE_IFADDR"]
+
+[[address_pools]]
+name = "dc1"
+subnet = "10.1.0.0/24"
+
+[[nodes]]
+name = "worker"
+address_pool = "dc1"
+
+[[nodes]]
+name = "seed"
+address = "10.1.0.1/24"
+"#,
+    )
+    .unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .arg("--topology")
+        .arg(&topology)
+        .arg("--stdout")
+        .arg(&stdout)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut lines: Vec<String> = std::fs::read_to_string(&stdout)
+        .unwrap()
+        .lines()
+        .map(Into::into)
+        .collect();
+    lines.sort();
+    assert_eq!(vec!["seed 10.1.0.1/24", "worker 10.1.0.2/24"], lines);
+    // isolated networks are not supported
+    std::fs::write(
+        &topology,
+        r#"
+command = "true"
+
+[[networks]]
+name = "dc1"
+subnet = "10.1.0.0/24"
+
+[[nodes]]
+network = "dc1"
+"#,
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .arg("--topology")
+        .arg(&topology)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not supported"));
+}
+
+#[test]
+fn cli_nodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let stdout = dir.path().join("stdout");
+    let status = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .arg("--stdout")
+        .arg(&stdout)
+        .args(["--node", "seed=echo seed"])
+        .args(["--node", "client*2"])
+        .args(["sh", "-c", "echo client $TESTNET_NODE_NAME"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut lines: Vec<String> = std::fs::read_to_string(&stdout)
+        .unwrap()
+        .lines()
+        .map(Into::into)
+        .collect();
+    lines.sort();
+    assert_eq!(vec!["client client1", "client client2", "seed"], lines);
+    let output = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .args(["--node", "good=true"])
+        .args(["--node", "bad=false"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("some nodes failed"), "{}", stderr);
+}
+
+#[test]
+fn cli_coordination() {
+    let dir = tempfile::tempdir().unwrap();
+    let stdout = dir.path().join("stdout");
+    let script = r#"
+if test "$TESTNET_NODE_INDEX" = 0; then
+    $TESTNET kv put address "$TESTNET_NODE_IPADDR"
+else
+    echo "address $($TESTNET kv get address)"
+fi
+$TESTNET barrier first
+$TESTNET broadcast-all "$TESTNET_NODE_NAME" | tr '\n' ' '
+echo
+"#;
+    let status = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .env("TESTNET", env!("CARGO_BIN_EXE_testnet"))
+        .arg("--stdout")
+        .arg(&stdout)
+        .args(["--nodes", "3", "sh", "-c", script])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mut lines: Vec<String> = std::fs::read_to_string(&stdout)
+        .unwrap()
+        .lines()
+        .map(Into::into)
+        .collect();
+    lines.sort();
+    assert_eq!(
+        vec![
+            "address 10.84.0.1",
+            "address 10.84.0.1",
+            "n0 n1 n2 ",
+            "n0 n1 n2 ",
+            "n0 n1 n2 "
+        ],
+        lines
+    );
+}
+
+#[test]
+fn cli_exec() {
+    // do not interfere with the networks of the other tests
+    let dir = tempfile::tempdir().unwrap();
+    let mut network = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .env("XDG_RUNTIME_DIR", dir.path())
+        .args(["--node", "a", "--node", "b", "sleep", "5"])
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    let output = loop {
+        let output = Command::new(env!("CARGO_BIN_EXE_testnet"))
+            .env("XDG_RUNTIME_DIR", dir.path())
+            .args(["exec", "b", "--", "hostname"])
+            .output()
+            .unwrap();
+        // wait until the network is published
+        if output.status.success() || start.elapsed() > Duration::from_secs(4) {
+            break output;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    assert!(network.wait().unwrap().success());
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!("b\n", String::from_utf8_lossy(&output.stdout));
+    // the directory is removed when the network exits
+    let runtime_dir = std::fs::read_dir(dir.path())
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(0, std::fs::read_dir(runtime_dir.path()).unwrap().count());
+}
+
+#[test]
+fn cli_up_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let testnet = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_testnet"))
+            .env("XDG_RUNTIME_DIR", dir.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let network = Command::new(env!("CARGO_BIN_EXE_testnet"))
+        .env("XDG_RUNTIME_DIR", dir.path())
+        .args(["up", "--nodes", "2"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    while !testnet(&["exec", "n1", "--", "true"]).status.success() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "network is not up"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // the nodes stay idle until the network is stopped
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(testnet(&["exec", "n0", "--", "true"]).status.success());
+    assert!(testnet(&["down"]).status.success());
+    let output = network.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    // the nodes that were stopped did not fail
+    assert!(!stderr.contains("failed"), "{}", stderr);
+    assert!(!testnet(&["down"]).status.success());
+    // other users must not be able to publish the networks
+    let runtime_dir = dir.path().join(format!("testnet-{}", getuid()));
+    std::fs::set_permissions(runtime_dir, Permissions::from_mode(0o755)).unwrap();
+    let output = testnet(&["down"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("insecure runtime directory"));
+}
+
+#[test]
+fn supported() {
+    for result in [testnet::is_supported(), testnet::is_supported_privileged()] {
+        match result {
+            Ok(_) => {}
+            Err(Error::Unsupported(reason)) => eprintln!("not supported: {}", reason),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+}
+
+#[test]
+fn privileged() {
+    let config = NetConfig {
+        main: |mut context| {
+            let i = context.current_node_index();
+            let all_data = context.broadcast_all_string(i.to_string())?;
+            assert_eq!(vec!["0", "1"], all_data);
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let options = NetOptions::default()
+        .with_privileged(true)
+        .with_skip_unsupported(true);
+    match testnet_with_options(config, options) {
+        Err(e) if e.is_skipped() => eprintln!("{}", e),
+        other => {
+            other.unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_broadcast() {
+    use testnet::async_main;
+    let config = NetConfig {
+        main: async_main(|mut context| async move {
+            let i = context.current_node_index();
+            match i {
+                0 => context.broadcast_one().send_string("ping".into()).await?,
+                _ => assert_eq!("ping", context.broadcast_one().recv_string().await?),
+            }
+            let all_data = context.broadcast_all_string(i.to_string()).await?;
+            for (i, data) in all_data.into_iter().enumerate() {
+                assert_eq!(i.to_string(), data);
+            }
+            Ok(())
+        }),
+        nodes: vec![NodeConfig::default(); 3],
+    };
+    testnet(config).unwrap();
+}
+
+#[cfg(feature = "macros")]
+#[testnet::test(nodes = 3, timeout = "10s")]
+fn test_attribute(mut context: testnet::Context) -> Result<(), Error> {
+    let all_data = context.broadcast_all(vec![context.current_node_index() as u8])?;
+    assert_eq!(vec![vec![0_u8], vec![1], vec![2]], all_data);
+    Ok(())
+}
+
+#[cfg(feature = "macros")]
+#[testnet::test(timeout = "0.5s")]
+#[should_panic(expected = "timed out after 500ms")]
+fn test_attribute_timeout(context: testnet::Context) {
+    if context.current_node_index() == 1 {
+        std::thread::sleep(Duration::from_secs(10));
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() {
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span;
+    use tracing::Event;
+    use tracing::Metadata;
+
+    /// Prints the messages of the events to stderr.
+    struct StderrSubscriber;
+
+    impl tracing::Subscriber for StderrSubscriber {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            struct Message;
+            impl Visit for Message {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        let _ = writeln!(std::io::stderr(), "{:?}", value);
+                    }
+                }
+            }
+            event.record(&mut Message);
+        }
+
+        fn enter(&self, _span: &span::Id) {}
+
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    let config = NetConfig {
+        main: |mut context| {
+            context.step("first");
+            context.broadcast_all(Vec::new())?;
+            Ok(())
+        },
+        nodes: vec![NodeConfig::default(); 2],
+    };
+    let outcomes = tracing::subscriber::with_default(StderrSubscriber, || testnet(config)).unwrap();
+    for outcome in outcomes.into_iter() {
+        assert!(outcome
+            .report
+            .output
+            .iter()
+            .any(|line| line == "step \"first\": ok"));
+    }
+}
+
 #[test]
 #[should_panic]
 fn handle_panic() {